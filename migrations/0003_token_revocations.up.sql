@@ -0,0 +1,12 @@
+-- Individually revoked access tokens, kept until the token would have expired anyway
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Every token of the subject issued before `revoked_before` is revoked
+CREATE TABLE IF NOT EXISTS subject_revocations (
+    subject VARCHAR(255) PRIMARY KEY,
+    revoked_before TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Pending signups cannot be represented without a status, they have to sign
+-- up again
+DELETE FROM users WHERE password IS NULL;
+
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_verified_password;
+ALTER TABLE users ALTER COLUMN password SET NOT NULL;
+ALTER TABLE users DROP COLUMN IF EXISTS status;
+DROP TYPE IF EXISTS user_status;
@@ -0,0 +1,12 @@
+-- Signup state of a user. Pending users exist from the signup request on but
+-- have no password until they verify their email.
+DO $$ BEGIN
+    CREATE TYPE user_status AS ENUM ('pending_verification', 'verified');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status user_status NOT NULL DEFAULT 'verified';
+ALTER TABLE users ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_verified_password;
+ALTER TABLE users ADD CONSTRAINT users_verified_password CHECK (status = 'pending_verification' OR password IS NOT NULL);
@@ -0,0 +1,7 @@
+-- Fails if addresses differing only in case were added since, the old
+-- constraint cannot tell them apart
+CREATE INDEX IF NOT EXISTS email_verifications_email_idx ON email_verifications (email);
+DROP INDEX IF EXISTS email_verifications_email_lower_idx;
+
+ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
+DROP INDEX IF EXISTS users_email_lower_key;
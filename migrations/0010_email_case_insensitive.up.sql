@@ -0,0 +1,8 @@
+-- Email addresses are unique regardless of case, `Bob@x.com` and `bob@x.com`
+-- are the same account. Creating the index fails if such duplicates already
+-- exist; `first-backend backfill-emails` lists them.
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (LOWER(email));
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_email_key;
+
+CREATE INDEX IF NOT EXISTS email_verifications_email_lower_idx ON email_verifications (LOWER(email));
+DROP INDEX IF EXISTS email_verifications_email_idx;
@@ -0,0 +1,3 @@
+DELETE FROM role_permissions WHERE permission = 'keys:rotate';
+DELETE FROM permissions WHERE name = 'keys:rotate';
+DROP TABLE IF EXISTS signing_keys;
@@ -0,0 +1,19 @@
+-- JWT signing keys generated by rotation, shared by every instance. The
+-- newest key old enough to be known everywhere signs; the others verify.
+CREATE TABLE IF NOT EXISTS signing_keys (
+    kid VARCHAR(128) PRIMARY KEY,
+    algorithm VARCHAR(16) NOT NULL,
+    -- PKCS#8 DER
+    private_key BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS signing_keys_algorithm_created_at_idx ON signing_keys (algorithm, created_at DESC);
+
+INSERT INTO permissions (name, description) VALUES
+    ('keys:rotate', 'Rotate the JWT signing key')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'keys:rotate')
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,92 @@
+use actix_web::http::Method;
+
+// Who may call a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    // Anyone, no session token is looked at
+    Public,
+    // Any valid session
+    Authenticated,
+    // A valid session holding at least one of these roles
+    AnyRole(&'static [&'static str]),
+    // A valid session whose roles grant this permission. Handlers check it
+    // again, the table only turns callers away early.
+    Permission(&'static str),
+}
+
+// Which request paths a rule covers
+#[derive(Debug, Clone, Copy)]
+pub enum PathMatch {
+    Exact(&'static str),
+    // Every path starting with the prefix, e.g. `/admin/` covers the whole scope
+    Prefix(&'static str),
+}
+
+impl PathMatch {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Exact(exact) => path == *exact,
+            PathMatch::Prefix(prefix) => path.starts_with(prefix),
+        }
+    }
+}
+
+pub struct AccessRule {
+    // `None` matches any method
+    pub method: Option<Method>,
+    pub path: PathMatch,
+    pub access: Access,
+}
+
+impl AccessRule {
+    fn new(method: Method, path: PathMatch, access: Access) -> Self {
+        Self { method: Some(method), path, access }
+    }
+
+    fn public(method: Method, path: PathMatch) -> Self {
+        Self::new(method, path, Access::Public)
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method) && self.path.matches(path)
+    }
+}
+
+// Access to routes that no rule matches
+const DEFAULT_ACCESS: Access = Access::Authenticated;
+
+// Who may call what, in one place. The first matching rule wins; routes
+// without a rule need a valid session.
+pub fn default_access_rules() -> Vec<AccessRule> {
+    use PathMatch::{Exact, Prefix};
+
+    vec![
+        AccessRule::public(Method::POST, Exact("/signup")),
+        AccessRule::public(Method::POST, Exact("/signin")),
+        AccessRule::public(Method::POST, Exact("/token/refresh")),
+        AccessRule::public(Method::POST, Exact("/password/forgot")),
+        // These validate the token from their email link themselves
+        AccessRule::public(Method::POST, Exact("/setpassword")),
+        AccessRule::public(Method::POST, Exact("/password/reset")),
+        AccessRule::public(Method::POST, Exact("/account/unlock")),
+        AccessRule::public(Method::GET, Prefix("/.well-known/")),
+        // Scraped by Prometheus, which has no session
+        AccessRule::public(Method::GET, Exact("/metrics")),
+        // Probed by the orchestrator, which has no session either
+        AccessRule::public(Method::GET, Exact("/healthz")),
+        AccessRule::public(Method::GET, Exact("/readyz")),
+        // Creates accounts without email verification
+        AccessRule::new(Method::POST, Exact("/users"), Access::Permission("users:create")),
+        AccessRule::new(Method::POST, Exact("/admin/keys/rotate"), Access::Permission("keys:rotate")),
+        // Anything else under /admin/ is for admins only
+        AccessRule { method: None, path: Prefix("/admin/"), access: Access::AnyRole(&["admin"]) },
+    ]
+}
+
+// Access required for a request
+pub fn required_access<'a>(rules: &'a [AccessRule], method: &Method, path: &str) -> &'a Access {
+    rules
+        .iter()
+        .find(|rule| rule.matches(method, path))
+        .map_or(&DEFAULT_ACCESS, |rule| &rule.access)
+}
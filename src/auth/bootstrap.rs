@@ -0,0 +1,62 @@
+use tracing::info;
+
+use crate::auth::email_normalizer::EmailNormalizer;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::config::settings::AdminSettings;
+use crate::error::AppError;
+use crate::models::user::CreateUserRequest;
+use crate::repositories::role_repository::RoleRepository;
+use crate::repositories::user_repository::{AuthError, UserRepository};
+
+// Errors raised while bootstrapping the admin account
+#[derive(Debug, thiserror::Error)]
+pub enum BootstrapError {
+    #[error("admin.email is not a valid email address: {0}")]
+    InvalidEmail(&'static str),
+    #[error("admin.password must be set to create the admin account")]
+    MissingPassword,
+    #[error("admin.password does not satisfy the password policy: {0:?}")]
+    WeakPassword(AppError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Failed to create the admin account: {0}")]
+    Auth(#[from] AuthError),
+}
+
+// Role given to the bootstrap account
+const ADMIN_ROLE: &str = "admin";
+
+// Makes sure the account named by `admin.email` exists and holds the admin
+// role, so a fresh deployment has someone who can manage users. The account
+// is created with `admin.password` if it does not exist yet; an existing
+// account keeps its password. Does nothing when `admin.email` is not set.
+pub async fn bootstrap_admin(
+    users: &UserRepository,
+    roles: &RoleRepository,
+    policy: &PasswordPolicy,
+    normalizer: &EmailNormalizer,
+    settings: &AdminSettings,
+) -> Result<(), BootstrapError> {
+    let Some(email) = &settings.email else {
+        return Ok(());
+    };
+    let email = normalizer.try_normalize(email).map_err(BootstrapError::InvalidEmail)?;
+
+    let user = match users.get_user_by_email(&email).await? {
+        Some(user) => user,
+        None => {
+            let password = settings.password.clone().ok_or(BootstrapError::MissingPassword)?;
+            policy
+                .validate("admin.password", &password, &[&email])
+                .await
+                .map_err(BootstrapError::WeakPassword)?;
+
+            info!("Creating admin account: {}", email);
+            users.create_user(CreateUserRequest { email, password }).await?
+        }
+    };
+
+    roles.grant_role(user.uid, ADMIN_ROLE).await?;
+    info!("Admin role granted to user: {}", user.uid);
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use actix_web::http::header::{HeaderMap, FORWARDED};
+use actix_web::HttpRequest;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// An address or CIDR range of reverse proxies whose forwarding headers are
+// believed, e.g. `10.0.0.7` or `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is not an IP address or CIDR range", value);
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Works out which client a request comes from, for keying rate limits and
+// sign-in throttling. The socket peer is the client unless it is a trusted
+// proxy; then the `Forwarded` (or `X-Forwarded-For`) chain is walked from the
+// nearest hop back, skipping trusted proxies, since only the entries they
+// appended can be believed.
+#[derive(Clone, Default)]
+pub struct ClientIpResolver {
+    trusted_proxies: Arc<Vec<TrustedProxy>>,
+}
+
+impl ClientIpResolver {
+    pub fn new(trusted_proxies: Vec<TrustedProxy>) -> Self {
+        Self { trusted_proxies: Arc::new(trusted_proxies) }
+    }
+
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip().to_canonical();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        for hop in forwarded_chain(req.headers()).into_iter().rev() {
+            // Garbage in the chain means nothing before it can be believed
+            let Some(hop) = hop else { break };
+            client = hop;
+            if !self.is_trusted(hop) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+    }
+}
+
+// Addresses the request was forwarded for, client first. `None` for entries
+// that are not an address (obfuscated identifiers, `unknown`).
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = headers.get_all(FORWARDED).filter_map(|value| value.to_str().ok()).collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+            })
+            .collect();
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+// `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `[2001:db8::1]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    let host = match node.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => node.rsplit_once(':')?.0,
+    };
+    host.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
@@ -0,0 +1,176 @@
+use crate::error::{AppError, FieldError};
+
+// Longest address that fits the users table and SMTP (RFC 5321)
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+// Mailbox providers whose addresses have aliases for the same inbox
+struct ProviderRule {
+    domains: &'static [&'static str],
+    // Domain every alias domain is rewritten to
+    canonical_domain: &'static str,
+    // `john.doe` and `johndoe` are the same mailbox
+    ignores_dots: bool,
+    // `john+news` delivers to `john`
+    plus_tags: bool,
+}
+
+const PROVIDER_RULES: &[ProviderRule] = &[
+    ProviderRule {
+        domains: &["gmail.com", "googlemail.com"],
+        canonical_domain: "gmail.com",
+        ignores_dots: true,
+        plus_tags: true,
+    },
+    ProviderRule {
+        domains: &["outlook.com", "hotmail.com", "live.com"],
+        canonical_domain: "",
+        ignores_dots: false,
+        plus_tags: true,
+    },
+    ProviderRule {
+        domains: &["icloud.com", "me.com", "mac.com"],
+        canonical_domain: "",
+        ignores_dots: false,
+        plus_tags: true,
+    },
+    ProviderRule {
+        domains: &["fastmail.com", "protonmail.com", "proton.me"],
+        canonical_domain: "",
+        ignores_dots: false,
+        plus_tags: true,
+    },
+];
+
+// Brings email addresses into the one form they are stored and looked up in:
+// surrounding whitespace trimmed and the domain lowercased and converted to
+// punycode. With `email.provider_rules` set, aliases of the big mailbox
+// providers (Gmail dots, plus tags) also collapse into one address, so the
+// same inbox cannot hold several accounts. The local part keeps its case;
+// the database compares addresses case-insensitively.
+pub struct EmailNormalizer {
+    provider_rules: bool,
+}
+
+impl EmailNormalizer {
+    pub fn new(provider_rules: bool) -> Self {
+        Self { provider_rules }
+    }
+
+    // Normalizes `email`, reporting a malformed address against `field`
+    pub fn normalize(&self, field: &str, email: &str) -> Result<String, AppError> {
+        self.try_normalize(email)
+            .map_err(|message| AppError::Validation(vec![FieldError::new(field, "invalid_email", message.to_string())]))
+    }
+
+    // Like `normalize`, with just the reason an address is malformed
+    pub fn try_normalize(&self, email: &str) -> Result<String, &'static str> {
+        let email = email.trim();
+        let (local, domain) = email.rsplit_once('@').ok_or("Must be an email address")?;
+
+        if local.is_empty() || local.chars().count() > MAX_LOCAL_PART_LENGTH {
+            return Err("Must have a name of 1 to 64 characters before the @");
+        }
+        if local.chars().any(|c| c.is_whitespace() || c.is_control() || c == '@') {
+            return Err("Must not contain spaces or a second @");
+        }
+
+        // Lowercases and converts internationalized domains to punycode
+        let domain = idna::domain_to_ascii(domain).map_err(|_| "Must have a valid domain after the @")?;
+        if domain.is_empty() || domain.split('.').any(str::is_empty) || !domain.contains('.') {
+            return Err("Must have a valid domain after the @");
+        }
+
+        let (local, domain) = if self.provider_rules {
+            apply_provider_rules(local, domain)
+        } else {
+            (local.to_string(), domain)
+        };
+
+        let normalized = format!("{}@{}", local, domain);
+        if normalized.len() > MAX_EMAIL_LENGTH {
+            return Err("Must be at most 254 characters long");
+        }
+        Ok(normalized)
+    }
+}
+
+fn apply_provider_rules(local: &str, domain: String) -> (String, String) {
+    let Some(rule) = PROVIDER_RULES.iter().find(|rule| rule.domains.contains(&domain.as_str())) else {
+        return (local.to_string(), domain);
+    };
+
+    let mut local = local;
+    if rule.plus_tags {
+        // Keep a lone `+name`, there is nothing left without it
+        if let Some((mailbox, _)) = local.split_once('+').filter(|(mailbox, _)| !mailbox.is_empty()) {
+            local = mailbox;
+        }
+    }
+    let local = if rule.ignores_dots { local.replace('.', "") } else { local.to_string() };
+
+    let domain = if rule.canonical_domain.is_empty() { domain } else { rule.canonical_domain.to_string() };
+    (local, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(email: &str) -> Result<String, &'static str> {
+        EmailNormalizer::new(true).try_normalize(email)
+    }
+
+    #[test]
+    fn lowercases_the_domain_and_keeps_the_local_part() {
+        assert_eq!(normalize("  John.Doe@Example.COM ").unwrap(), "John.Doe@example.com");
+        assert_eq!(normalize("user@Bücher.example").unwrap(), "user@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn drops_gmail_dots() {
+        assert_eq!(normalize("john.doe@gmail.com").unwrap(), "johndoe@gmail.com");
+        assert_eq!(normalize("j.o.h.n@GoogleMail.com").unwrap(), "john@gmail.com");
+        // Other providers treat dots as part of the name
+        assert_eq!(normalize("john.doe@outlook.com").unwrap(), "john.doe@outlook.com");
+    }
+
+    #[test]
+    fn strips_plus_tags() {
+        assert_eq!(normalize("john.doe+news@gmail.com").unwrap(), "johndoe@gmail.com");
+        assert_eq!(normalize("john+a+b@outlook.com").unwrap(), "john@outlook.com");
+        assert_eq!(normalize("+news@gmail.com").unwrap(), "+news@gmail.com");
+        assert_eq!(normalize("john+news@example.com").unwrap(), "john+news@example.com");
+    }
+
+    #[test]
+    fn leaves_aliases_alone_without_provider_rules() {
+        let normalizer = EmailNormalizer::new(false);
+        assert_eq!(normalizer.try_normalize("John.Doe+news@GMAIL.com").unwrap(), "John.Doe+news@gmail.com");
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for email in [
+            "",
+            "john.example.com",
+            "@example.com",
+            "john doe@example.com",
+            "john@",
+            "john@localhost",
+            "john@example..com",
+            &format!("{}@example.com", "a".repeat(65)),
+            &format!("john@{}.com", "a".repeat(250)),
+        ] {
+            assert!(normalize(email).is_err(), "{:?} was accepted", email);
+        }
+    }
+
+    #[test]
+    fn reports_the_field() {
+        let error = EmailNormalizer::new(false).normalize("email", "not an address").unwrap_err();
+        let AppError::Validation(fields) = error else { panic!("expected a validation error") };
+        assert_eq!(fields[0].field, "email");
+        assert_eq!(fields[0].code, "invalid_email");
+    }
+}
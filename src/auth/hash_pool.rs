@@ -0,0 +1,169 @@
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Errors from running a job on the hash pool
+#[derive(Debug, thiserror::Error)]
+pub enum HashError {
+    #[error("Password hashing queue is full")]
+    Busy,
+    #[error("Password hashing failed: {0}")]
+    Failed(String),
+}
+
+// Dedicated threads for Argon2. Hashing is deliberately slow and memory hard,
+// so running it on the actix workers would let a burst of logins stall every
+// other request. Jobs wait in a bounded queue; when it is full callers get
+// `HashError::Busy` instead of piling up.
+pub struct HashPool {
+    sender: SyncSender<Job>,
+    workers: usize,
+    queue_limit: usize,
+    metrics: Arc<HashPoolMetrics>,
+}
+
+impl HashPool {
+    pub fn new(workers: usize, queue_limit: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_limit);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("argon2-{}", i))
+                .spawn(move || worker(receiver))
+                .expect("Failed to spawn hash worker");
+        }
+
+        Self { sender, workers, queue_limit, metrics: Arc::default() }
+    }
+
+    // Runs `job` on a pool thread and waits for its result without blocking
+    // the executor
+    pub async fn run<F, R>(&self, job: F) -> Result<R, HashError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let metrics = self.metrics.clone();
+        let enqueued_at = Instant::now();
+
+        let wrapped: Job = Box::new(move || {
+            metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            metrics.running.fetch_add(1, Ordering::Relaxed);
+            metrics.queue_wait.observe(enqueued_at.elapsed());
+
+            let started_at = Instant::now();
+            let result = job();
+            metrics.duration.observe(started_at.elapsed());
+
+            metrics.running.fetch_sub(1, Ordering::Relaxed);
+            let _ = result_tx.send(result);
+        });
+
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.sender.try_send(wrapped) {
+            self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(match e {
+                TrySendError::Full(_) => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    HashError::Busy
+                }
+                TrySendError::Disconnected(_) => HashError::Failed("hash pool has shut down".to_string()),
+            });
+        }
+
+        // The sender is dropped without a result if the job panicked
+        result_rx
+            .await
+            .map_err(|_| HashError::Failed("hash job panicked".to_string()))
+    }
+
+    // Prometheus text exposition of the pool metrics
+    pub fn render_metrics(&self) -> String {
+        let metrics = &self.metrics;
+        let mut out = String::new();
+
+        gauge(&mut out, "password_hash_workers", "Threads dedicated to password hashing", self.workers as u64);
+        gauge(&mut out, "password_hash_queue_limit", "Hash jobs that may wait before requests are rejected", self.queue_limit as u64);
+        gauge(&mut out, "password_hash_queue_depth", "Hash jobs waiting for a worker", metrics.queued.load(Ordering::Relaxed) as u64);
+        gauge(&mut out, "password_hash_running", "Hash jobs currently running", metrics.running.load(Ordering::Relaxed) as u64);
+
+        let _ = writeln!(out, "# HELP password_hash_rejected_total Hash jobs rejected because the queue was full");
+        let _ = writeln!(out, "# TYPE password_hash_rejected_total counter");
+        let _ = writeln!(out, "password_hash_rejected_total {}", metrics.rejected.load(Ordering::Relaxed));
+
+        metrics.queue_wait.render(&mut out, "password_hash_queue_wait_seconds", "Time hash jobs spent waiting for a worker");
+        metrics.duration.render(&mut out, "password_hash_duration_seconds", "Time spent hashing or verifying a password");
+        out
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // Hold the lock only while taking the next job
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // Keep the thread alive if a job panics
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[derive(Default)]
+struct HashPoolMetrics {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    rejected: AtomicU64,
+    queue_wait: Histogram,
+    duration: Histogram,
+}
+
+// Upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Per bucket counts, the last slot counts observations above every bound
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.counts[BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
@@ -1,42 +1,144 @@
 use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, decode, DecodingKey, EncodingKey, Header, Validation, errors::Error as JwtError};
+use jsonwebtoken::{encode, decode, decode_header, Header, Validation, errors::{Error as JwtError, ErrorKind}};
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::auth::keys::JwtKeys;
+use crate::models::role::Grants;
+
+// What a token may be used for. A token is only accepted where its purpose
+// is expected, so e.g. an emailed verification link is not a session token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenPurpose {
+    Session,
+    VerifyEmail,
+    PasswordReset,
+    UnlockAccount,
+}
+
+impl TokenPurpose {
+    pub fn lifetime(self) -> Duration {
+        match self {
+            TokenPurpose::Session => Duration::hours(1),
+            TokenPurpose::VerifyEmail => Duration::hours(24),
+            TokenPurpose::PasswordReset => Duration::hours(1),
+            TokenPurpose::UnlockAccount => Duration::hours(1),
+        }
+    }
+
+    // How long the longest lived token stays valid
+    pub fn longest_lifetime() -> Duration {
+        [TokenPurpose::Session, TokenPurpose::VerifyEmail, TokenPurpose::PasswordReset, TokenPurpose::UnlockAccount]
+            .into_iter()
+            .map(TokenPurpose::lifetime)
+            .max()
+            .unwrap()
+    }
+}
 
 // claims structure that will be encoded in the JWT
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: i64,
     pub iat: i64,
+    pub jti: Uuid,  // unique token id, used for revocation
+    pub purpose: TokenPurpose,
+    // Id of the user, only set on session tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<Uuid>,
+    // Roles granted to the subject, checked against the route access rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    // Permissions those roles grant, checked by handlers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    // Narrows what the token may be used for, empty means unrestricted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
 }
 
-const JWT_SECRET: &[u8] = b"my-secret-key";
-const TOKEN_EXPIRATION_TIME: Duration = Duration::seconds(3600);
+pub fn generate_token(keys: &JwtKeys, email: String, purpose: TokenPurpose) -> Result<String, JwtError> {
+    issue_token(keys, email, purpose).map(|(token, _)| token)
+}
 
-pub fn generate_token(email: String) -> Result<String, JwtError> {
-    let now = Utc::now();
-    let expires_at = now + Duration::seconds(TOKEN_EXPIRATION_TIME.num_seconds());
+// Like `generate_token`, but also returns the claims so callers can record
+// the token id and expiry
+pub fn issue_token(keys: &JwtKeys, email: String, purpose: TokenPurpose) -> Result<(String, Claims), JwtError> {
+    sign(keys, new_claims(email, purpose))
+}
 
-    // preparing claims for the token
+// Session token identifying the user by id as well as email, carrying the
+// roles and permissions authorization is checked against. Changes to them
+// show up once the token is refreshed.
+pub fn generate_session_token(
+    keys: &JwtKeys,
+    uid: Uuid,
+    email: String,
+    grants: Grants,
+) -> Result<String, JwtError> {
     let claims = Claims {
+        uid: Some(uid),
+        roles: grants.roles,
+        permissions: grants.permissions,
+        ..new_claims(email, TokenPurpose::Session)
+    };
+    sign(keys, claims).map(|(token, _)| token)
+}
+
+fn new_claims(email: String, purpose: TokenPurpose) -> Claims {
+    let now = Utc::now();
+    let expires_at = now + purpose.lifetime();
+
+    Claims {
         sub: email,
         exp: expires_at.timestamp(),
         iat: now.timestamp(),
-    };
+        jti: Uuid::new_v4(),
+        purpose,
+        uid: None,
+        roles: Vec::new(),
+        permissions: Vec::new(),
+        scopes: Vec::new(),
+    }
+}
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET),
-    )
+fn sign(keys: &JwtKeys, claims: Claims) -> Result<(String, Claims), JwtError> {
+    // sign with the active key and advertise it in the header
+    let key = keys.active();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
 
+    let encoding_key = key
+        .encoding_key()
+        .ok_or_else(|| JwtError::from(ErrorKind::InvalidKeyFormat))?;
+
+    let token = encode(&header, &claims, encoding_key)?;
+    Ok((token, claims))
 }
 
-pub fn validate_token(token: String) -> Result<Claims, JwtError> {
-    decode::<Claims>(
+pub fn validate_token(keys: &JwtKeys, token: String, purpose: TokenPurpose) -> Result<Claims, JwtError> {
+    // pick the verification key named by the token's `kid` header
+    let header = decode_header(&token)?;
+    let key = header
+        .kid
+        .as_deref()
+        .and_then(|kid| keys.get(kid))
+        .ok_or_else(|| JwtError::from(ErrorKind::InvalidToken))?;
+
+    // only accept the algorithm the key was issued for
+    let claims = decode::<Claims>(
         &token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &Validation::default(),
-    )
-        .map(|data| data.claims)
-}
\ No newline at end of file
+        key.decoding_key(),
+        &Validation::new(key.algorithm),
+    )?
+        .claims;
+
+    // reject tokens issued for a different purpose
+    if claims.purpose != purpose {
+        return Err(JwtError::from(ErrorKind::InvalidToken));
+    }
+
+    Ok(claims)
+}
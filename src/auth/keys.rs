@@ -0,0 +1,557 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use ring::rand::SystemRandom;
+use ring::signature::{self, KeyPair, RsaPublicKeyComponents};
+use serde_json::{json, Value};
+use sqlx::{PgPool, Postgres, Transaction};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::info;
+
+use crate::config::settings::JwtSettings;
+
+// Errors raised while loading JWT key material
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("Missing setting: {0}")]
+    MissingSetting(&'static str),
+    #[error("Unsupported JWT algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Failed to read key file {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("Invalid key material: {0}")]
+    InvalidKey(String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+// Public half of an asymmetric key, used to derive the key id and to publish
+// the key in the JWKS document
+#[derive(Debug, Clone)]
+pub enum PublicKeyMaterial {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ec { x: Vec<u8>, y: Vec<u8> },
+    Ed { x: Vec<u8> },
+}
+
+// A single key identified by its `kid`. Verification-only keys have no
+// encoding key; symmetric keys have no public material.
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub public: Option<PublicKeyMaterial>,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+impl SigningKey {
+    // Builds an HS256 key from a shared secret
+    pub fn hmac(secret: &[u8], kid: Option<String>) -> Self {
+        let mut key = Self::hmac_verification(secret, kid);
+        key.encoding = Some(EncodingKey::from_secret(secret));
+        key
+    }
+
+    // Builds a verification-only HS256 key from a retired shared secret
+    pub fn hmac_verification(secret: &[u8], kid: Option<String>) -> Self {
+        let kid = kid.unwrap_or_else(|| thumbprint(&[("k", URL_SAFE_NO_PAD.encode(secret)), ("kty", "oct".to_string())]));
+        Self {
+            kid,
+            algorithm: Algorithm::HS256,
+            public: None,
+            encoding: None,
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    // Builds an asymmetric key from a PEM encoded private key. The public key
+    // is derived from the private key so only one file needs to be provided.
+    pub fn from_private_pem(algorithm: Algorithm, pem_bytes: &[u8], kid: Option<String>) -> Result<Self, KeyError> {
+        let parsed = pem::parse(pem_bytes).map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+
+        if algorithm != Algorithm::RS256 {
+            return Self::from_pkcs8(algorithm, parsed.contents(), kid);
+        }
+
+        let key_pair = if parsed.tag() == "RSA PRIVATE KEY" {
+            signature::RsaKeyPair::from_der(parsed.contents()).map_err(rejected)?
+        } else {
+            signature::RsaKeyPair::from_pkcs8(parsed.contents()).map_err(rejected)?
+        };
+        let components = RsaPublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+        let public = PublicKeyMaterial::Rsa { n: components.n, e: components.e };
+
+        Self::asymmetric(algorithm, Some(EncodingKey::from_rsa_pem(pem_bytes)?), public, kid)
+    }
+
+    // Builds an ES256 or EdDSA key from a PKCS#8 document
+    fn from_pkcs8(algorithm: Algorithm, der: &[u8], kid: Option<String>) -> Result<Self, KeyError> {
+        let (encoding, public) = match algorithm {
+            Algorithm::ES256 => {
+                let key_pair = signature::EcdsaKeyPair::from_pkcs8(
+                    &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                    der,
+                    &SystemRandom::new(),
+                ).map_err(rejected)?;
+                let public = ec_point(key_pair.public_key().as_ref())?;
+                (EncodingKey::from_ec_der(der), public)
+            }
+            Algorithm::EdDSA => {
+                let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).map_err(rejected)?;
+                let public = PublicKeyMaterial::Ed { x: key_pair.public_key().as_ref().to_vec() };
+                (EncodingKey::from_ed_der(der), public)
+            }
+            other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+        };
+
+        Self::asymmetric(algorithm, Some(encoding), public, kid)
+    }
+
+    // Builds a verification-only key from a PEM encoded public key
+    // (SubjectPublicKeyInfo, or PKCS#1 for RSA)
+    pub fn from_public_pem(algorithm: Algorithm, pem_bytes: &[u8], kid: Option<String>) -> Result<Self, KeyError> {
+        let parsed = pem::parse(pem_bytes).map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+        let invalid = || KeyError::InvalidKey("malformed public key".to_string());
+
+        let key = if parsed.tag() == "RSA PUBLIC KEY" {
+            parsed.contents()
+        } else {
+            spki_public_key(parsed.contents()).ok_or_else(invalid)?
+        };
+
+        let public = match algorithm {
+            Algorithm::RS256 => {
+                let (n, e) = rsa_public_key(key).ok_or_else(invalid)?;
+                PublicKeyMaterial::Rsa { n, e }
+            }
+            Algorithm::ES256 => ec_point(key)?,
+            Algorithm::EdDSA if key.len() == 32 => PublicKeyMaterial::Ed { x: key.to_vec() },
+            Algorithm::EdDSA => return Err(invalid()),
+            other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+        };
+
+        Self::asymmetric(algorithm, None, public, kid)
+    }
+
+    // Generates a fresh ES256 or EdDSA key as a PKCS#8 document. HS256
+    // secrets cannot be published and RSA keys cannot be generated here, so
+    // those keys are provisioned as files.
+    pub fn generate_pkcs8(algorithm: Algorithm) -> Result<Vec<u8>, KeyError> {
+        let rng = SystemRandom::new();
+        let failed = |_| KeyError::InvalidKey("key generation failed".to_string());
+
+        let pkcs8 = match algorithm {
+            Algorithm::ES256 => {
+                signature::EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(failed)?
+            }
+            Algorithm::EdDSA => signature::Ed25519KeyPair::generate_pkcs8(&rng).map_err(failed)?,
+            other => return Err(KeyError::UnsupportedAlgorithm(format!("cannot generate {:?} keys", other))),
+        };
+        Ok(pkcs8.as_ref().to_vec())
+    }
+
+    fn asymmetric(
+        algorithm: Algorithm,
+        encoding: Option<EncodingKey>,
+        public: PublicKeyMaterial,
+        kid: Option<String>,
+    ) -> Result<Self, KeyError> {
+        let decoding = decoding_key(&public)?;
+        let kid = kid.unwrap_or_else(|| public_thumbprint(&public));
+        Ok(Self { kid, algorithm, public: Some(public), encoding, decoding })
+    }
+
+    pub fn encoding_key(&self) -> Option<&EncodingKey> {
+        self.encoding.as_ref()
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    // JWK representation of the public key, `None` for symmetric keys
+    pub fn to_jwk(&self) -> Option<Value> {
+        let mut jwk = match self.public.as_ref()? {
+            PublicKeyMaterial::Rsa { n, e } => json!({
+                "kty": "RSA",
+                "n": URL_SAFE_NO_PAD.encode(n),
+                "e": URL_SAFE_NO_PAD.encode(e),
+            }),
+            PublicKeyMaterial::Ec { x, y } => json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(x),
+                "y": URL_SAFE_NO_PAD.encode(y),
+            }),
+            PublicKeyMaterial::Ed { x } => json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(x),
+            }),
+        };
+        jwk["kid"] = json!(self.kid);
+        jwk["alg"] = json!(format!("{:?}", self.algorithm));
+        jwk["use"] = json!("sig");
+        Some(jwk)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for KeyError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        KeyError::InvalidKey(e.to_string())
+    }
+}
+
+fn rejected(e: ring::error::KeyRejected) -> KeyError {
+    KeyError::InvalidKey(e.to_string())
+}
+
+// Splits an uncompressed P-256 point (0x04 || x || y) into its coordinates
+fn ec_point(point: &[u8]) -> Result<PublicKeyMaterial, KeyError> {
+    match point {
+        [0x04, coordinates @ ..] if coordinates.len() == 64 => Ok(PublicKeyMaterial::Ec {
+            x: coordinates[..32].to_vec(),
+            y: coordinates[32..].to_vec(),
+        }),
+        _ => Err(KeyError::InvalidKey("expected an uncompressed P-256 point".to_string())),
+    }
+}
+
+fn decoding_key(public: &PublicKeyMaterial) -> Result<DecodingKey, KeyError> {
+    Ok(match public {
+        PublicKeyMaterial::Rsa { n, e } => DecodingKey::from_rsa_raw_components(n, e),
+        PublicKeyMaterial::Ec { x, y } => {
+            DecodingKey::from_ec_components(&URL_SAFE_NO_PAD.encode(x), &URL_SAFE_NO_PAD.encode(y))?
+        }
+        PublicKeyMaterial::Ed { x } => DecodingKey::from_ed_components(&URL_SAFE_NO_PAD.encode(x))?,
+    })
+}
+
+// Splits a DER element into its tag, contents and the remaining input
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets].iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, &rest[octets..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+// Extracts the subjectPublicKey bits from a SubjectPublicKeyInfo structure
+fn spki_public_key(der: &[u8]) -> Option<&[u8]> {
+    let (0x30, spki, _) = der_element(der)? else { return None };
+    let (0x30, _, rest) = der_element(spki)? else { return None };
+    let (0x03, bits, _) = der_element(rest)? else { return None };
+    match bits.split_first()? {
+        (0, key) => Some(key),
+        _ => None,
+    }
+}
+
+// Extracts the modulus and exponent from a PKCS#1 RSAPublicKey structure
+fn rsa_public_key(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let strip = |int: &[u8]| int.iter().skip_while(|b| **b == 0).copied().collect::<Vec<_>>();
+    let (0x30, key, _) = der_element(der)? else { return None };
+    let (0x02, n, rest) = der_element(key)? else { return None };
+    let (0x02, e, _) = der_element(rest)? else { return None };
+    Some((strip(n), strip(e)))
+}
+
+// RFC 7638 JWK thumbprint of the public key, used as the default key id
+fn public_thumbprint(public: &PublicKeyMaterial) -> String {
+    match public {
+        PublicKeyMaterial::Rsa { n, e } => thumbprint(&[
+            ("e", URL_SAFE_NO_PAD.encode(e)),
+            ("kty", "RSA".to_string()),
+            ("n", URL_SAFE_NO_PAD.encode(n)),
+        ]),
+        PublicKeyMaterial::Ec { x, y } => thumbprint(&[
+            ("crv", "P-256".to_string()),
+            ("kty", "EC".to_string()),
+            ("x", URL_SAFE_NO_PAD.encode(x)),
+            ("y", URL_SAFE_NO_PAD.encode(y)),
+        ]),
+        PublicKeyMaterial::Ed { x } => thumbprint(&[
+            ("crv", "Ed25519".to_string()),
+            ("kty", "OKP".to_string()),
+            ("x", URL_SAFE_NO_PAD.encode(x)),
+        ]),
+    }
+}
+
+// Members must be given in lexicographic order, as required by RFC 7638
+fn thumbprint(members: &[(&str, String)]) -> String {
+    let body = members
+        .iter()
+        .map(|(name, value)| format!("\"{}\":\"{}\"", name, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    URL_SAFE_NO_PAD.encode(Sha256::digest(format!("{{{}}}", body)))
+}
+
+// How often every instance reloads the rotated keys from the database
+pub const KEY_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// A rotated key only starts signing once it is this old, so every instance
+// has loaded it and clients caching the JWKS (for 5 minutes) have fetched it
+// before its tokens show up
+pub const ACTIVATION_DELAY: Duration = Duration::from_secs(600);
+
+// Advisory lock serializing rotations between instances
+const ROTATION_LOCK_ID: i64 = 0x6a77_745f_726f_7461;
+
+struct KeyRingState {
+    active: Arc<SigningKey>,
+    // Newest first
+    verification: Vec<Arc<SigningKey>>,
+}
+
+// Key ring holding one active signing key plus verification-only keys. The
+// configured key signs until the first rotation. Rotated keys are stored in
+// Postgres, so every instance loads the same ring and keeps it across
+// restarts. A key replaced by rotation still verifies for the next
+// `max_verification_keys` rotations; keys from `verification_key_files` are
+// always accepted.
+pub struct JwtKeys {
+    pool: PgPool,
+    state: RwLock<KeyRingState>,
+    configured: Arc<SigningKey>,
+    configured_verification: Vec<Arc<SigningKey>>,
+    max_verification_keys: usize,
+    rotation_interval: Option<Duration>,
+}
+
+impl JwtKeys {
+    // Loads the key ring described by the `[jwt]` settings plus the keys
+    // rotated so far. The settings must have passed `Settings::load`, which
+    // checks the HS256 secret lengths.
+    pub async fn load(settings: &JwtSettings, pool: PgPool) -> Result<Self, KeyError> {
+        let kid = settings.key_id.clone();
+
+        let (algorithm, active) = match settings.algorithm.as_str() {
+            "HS256" => {
+                let secret = match (&settings.secret_file, &settings.secret) {
+                    (Some(path), _) => read_file(path)?,
+                    (None, Some(secret)) => secret.clone().into_bytes(),
+                    (None, None) => return Err(KeyError::MissingSetting("jwt.secret")),
+                };
+                (Algorithm::HS256, SigningKey::hmac(secret.trim_ascii(), kid))
+            }
+            "RS256" | "ES256" | "EdDSA" => {
+                let path = settings
+                    .private_key_file
+                    .as_ref()
+                    .ok_or(KeyError::MissingSetting("jwt.private_key_file"))?;
+                let algorithm = settings.algorithm.parse::<Algorithm>()?;
+                (algorithm, SigningKey::from_private_pem(algorithm, &read_file(path)?, kid)?)
+            }
+            other => return Err(KeyError::UnsupportedAlgorithm(other.to_string())),
+        };
+
+        let verification = settings
+            .verification_key_files
+            .iter()
+            .map(|path| {
+                let contents = read_file(path)?;
+                match algorithm {
+                    Algorithm::HS256 => Ok(SigningKey::hmac_verification(contents.trim_ascii(), None)),
+                    _ => SigningKey::from_public_pem(algorithm, &contents, None),
+                }
+            })
+            .collect::<Result<Vec<_>, KeyError>>()?;
+
+        let active = Arc::new(active);
+        let verification: Vec<Arc<SigningKey>> = verification.into_iter().map(Arc::new).collect();
+        let keys = Self {
+            pool,
+            state: RwLock::new(KeyRingState { active: active.clone(), verification: verification.clone() }),
+            configured: active,
+            configured_verification: verification,
+            max_verification_keys: settings.max_verification_keys,
+            rotation_interval: settings.rotation_interval_secs.map(Duration::from_secs),
+        };
+        keys.sync().await?;
+
+        let state = keys.state.read().unwrap();
+        info!(
+            "Loaded {:?} JWT signing key with kid {} and {} verification key(s)",
+            state.active.algorithm,
+            state.active.kid,
+            state.verification.len()
+        );
+        drop(state);
+        Ok(keys)
+    }
+
+    // Only generated keys can be rotated, see `SigningKey::generate_pkcs8`
+    pub fn can_rotate(&self) -> bool {
+        matches!(self.configured.algorithm, Algorithm::ES256 | Algorithm::EdDSA)
+    }
+
+    // Reloads the rotated keys, picking up rotations by other instances
+    pub async fn sync(&self) -> Result<(), KeyError> {
+        if !self.can_rotate() {
+            return Ok(());
+        }
+        let algorithm = self.configured.algorithm;
+
+        // Keys still waiting to activate, the active one and the retired ones
+        // that still verify
+        let rows = sqlx::query!(
+            r#"
+            SELECT kid, private_key, created_at <= NOW() - make_interval(secs => $2) AS "activated!"
+            FROM signing_keys
+            WHERE algorithm = $1
+            ORDER BY created_at DESC
+            LIMIT $3
+            "#,
+            algorithm_name(algorithm),
+            ACTIVATION_DELAY.as_secs_f64(),
+            (self.max_verification_keys + 2) as i64
+        )
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut pending = Vec::new();
+        let mut activated = Vec::new();
+        for row in rows {
+            let key = Arc::new(SigningKey::from_pkcs8(algorithm, &row.private_key, Some(row.kid))?);
+            if row.activated {
+                activated.push(key);
+            } else {
+                pending.push(key);
+            }
+        }
+        // The configured key is older than any rotated one
+        activated.push(self.configured.clone());
+
+        let active = activated.remove(0);
+        let verification = pending
+            .into_iter()
+            .chain(activated.into_iter().take(self.max_verification_keys))
+            .chain(self.configured_verification.iter().cloned())
+            .collect();
+        *self.state.write().unwrap() = KeyRingState { active, verification };
+        Ok(())
+    }
+
+    pub fn active(&self) -> Arc<SigningKey> {
+        self.state.read().unwrap().active.clone()
+    }
+
+    // Looks up a signing or verification key by id
+    pub fn get(&self, kid: &str) -> Option<Arc<SigningKey>> {
+        let state = self.state.read().unwrap();
+        std::iter::once(&state.active)
+            .chain(state.verification.iter())
+            .find(|key| key.kid == kid)
+            .cloned()
+    }
+
+    // Generates a new signing key for every instance and returns its id. It
+    // is published right away and signs after `ACTIVATION_DELAY`.
+    pub async fn rotate(&self) -> Result<String, KeyError> {
+        let mut tx = self.begin_rotation().await?;
+        let kid = self.store_new_key(&mut tx).await?;
+        tx.commit().await?;
+
+        self.sync().await?;
+        Ok(kid)
+    }
+
+    // Rotates when `rotation_interval` has passed since the newest key was
+    // generated, by this or any other instance
+    pub async fn rotate_if_due(&self) -> Result<Option<String>, KeyError> {
+        let Some(interval) = self.rotation_interval else {
+            return Ok(None);
+        };
+
+        let mut tx = self.begin_rotation().await?;
+        let due = sqlx::query_scalar!(
+            r#"
+            SELECT NOT EXISTS (
+                SELECT 1 FROM signing_keys
+                WHERE algorithm = $1 AND created_at > NOW() - make_interval(secs => $2)
+            ) AS "due!"
+            "#,
+            algorithm_name(self.configured.algorithm),
+            interval.as_secs_f64()
+        )
+            .fetch_one(&mut *tx)
+            .await?;
+        if !due {
+            return Ok(None);
+        }
+        let kid = self.store_new_key(&mut tx).await?;
+        tx.commit().await?;
+
+        self.sync().await?;
+        Ok(Some(kid))
+    }
+
+    async fn begin_rotation(&self) -> Result<Transaction<'static, Postgres>, KeyError> {
+        if !self.can_rotate() {
+            return Err(KeyError::UnsupportedAlgorithm(format!(
+                "rotation of {:?} keys",
+                self.configured.algorithm
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("SELECT pg_advisory_xact_lock($1)", ROTATION_LOCK_ID)
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx)
+    }
+
+    async fn store_new_key(&self, tx: &mut Transaction<'static, Postgres>) -> Result<String, KeyError> {
+        let algorithm = self.configured.algorithm;
+        let pkcs8 = SigningKey::generate_pkcs8(algorithm)?;
+        let key = SigningKey::from_pkcs8(algorithm, &pkcs8, None)?;
+
+        sqlx::query!(
+            "INSERT INTO signing_keys (kid, algorithm, private_key) VALUES ($1, $2, $3)",
+            key.kid,
+            algorithm_name(algorithm),
+            pkcs8
+        )
+            .execute(&mut **tx)
+            .await?;
+
+        info!("Generated JWT signing key {}, signing in {:?}", key.kid, ACTIVATION_DELAY);
+        Ok(key.kid)
+    }
+
+    // JSON Web Key Set with the public half of every asymmetric key
+    pub fn jwks(&self) -> Value {
+        let state = self.state.read().unwrap();
+        let keys: Vec<Value> = std::iter::once(&state.active)
+            .chain(state.verification.iter())
+            .filter_map(|key| key.to_jwk())
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+fn algorithm_name(algorithm: Algorithm) -> String {
+    format!("{:?}", algorithm)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, KeyError> {
+    fs::read(path).map_err(|e| KeyError::Io(path.display().to_string(), e))
+}
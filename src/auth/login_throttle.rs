@@ -0,0 +1,153 @@
+use sqlx::PgPool;
+use std::net::IpAddr;
+
+use crate::config::settings::LoginThrottleSettings;
+use crate::error::AppError;
+
+// What a failed sign-in led to
+pub struct FailureOutcome {
+    // The account has just been locked, the owner should be told how to unlock it
+    pub account_locked: bool,
+}
+
+// Brute-force protection for sign-in. Failures are counted per account and per
+// client IP; once a counter reaches its threshold the key is locked, and every
+// further failure after the lock expires doubles the lockout up to a cap.
+// Counters are kept in Postgres so all instances share them.
+pub struct LoginThrottle {
+    pool: PgPool,
+    max_account_failures: i32,
+    max_ip_failures: i32,
+    // Lockout after the first failure over the threshold
+    base_lockout_secs: f64,
+    max_lockout_secs: f64,
+    // Counters are reset after this long without a failure
+    failure_window_secs: f64,
+}
+
+impl LoginThrottle {
+    pub fn new(pool: PgPool, settings: &LoginThrottleSettings) -> Self {
+        Self {
+            pool,
+            max_account_failures: settings.max_account_failures,
+            max_ip_failures: settings.max_ip_failures,
+            base_lockout_secs: settings.lockout_secs,
+            max_lockout_secs: settings.max_lockout_secs,
+            failure_window_secs: settings.failure_window_secs,
+        }
+    }
+
+    // Rejects the attempt while the account or the client IP is locked
+    pub async fn check(&self, email: &str, ip: Option<IpAddr>) -> Result<(), AppError> {
+        let locks = sqlx::query!(
+            r#"
+            SELECT key, CEIL(EXTRACT(EPOCH FROM locked_until - NOW()))::BIGINT AS "retry_after!"
+            FROM login_failures
+            WHERE key = ANY($1) AND locked_until > NOW()
+            "#,
+            &keys(email, ip) as &[String]
+        )
+            .fetch_all(&self.pool)
+            .await?;
+
+        // The account lock wins, it is the one the user can do something about
+        let account_key = account_key(email);
+        if let Some(lock) = locks.iter().find(|lock| lock.key == account_key) {
+            return Err(AppError::AccountLocked(lock.retry_after.max(1) as u64));
+        }
+        if let Some(lock) = locks.first() {
+            return Err(AppError::TooManyAttempts(lock.retry_after.max(1) as u64));
+        }
+        Ok(())
+    }
+
+    pub async fn record_failure(&self, email: &str, ip: Option<IpAddr>) -> Result<FailureOutcome, sqlx::Error> {
+        let account_locked = self.fail(&account_key(email), self.max_account_failures).await?;
+        if let Some(ip) = ip {
+            self.fail(&ip_key(ip), self.max_ip_failures).await?;
+        }
+        Ok(FailureOutcome { account_locked })
+    }
+
+    // A successful sign-in proves the account is in the right hands. The IP
+    // counter is left to age out, otherwise an attacker could reset it by
+    // signing into an account of their own between guesses.
+    pub async fn record_success(&self, email: &str) -> Result<(), sqlx::Error> {
+        self.unlock(email).await
+    }
+
+    // Clears the account's counter and lock, e.g. from the emailed unlock link
+    pub async fn unlock(&self, email: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM login_failures WHERE key = $1", account_key(email))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Drops counters that have aged out of the failure window
+    pub async fn purge(&self) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM login_failures
+            WHERE GREATEST(last_failure_at, locked_until) < NOW() - make_interval(secs => $1)
+            "#,
+            self.failure_window_secs
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Counts a failure for `key` and locks it once `threshold` is reached.
+    // Returns whether the key got locked.
+    async fn fail(&self, key: &str, threshold: i32) -> Result<bool, sqlx::Error> {
+        let failures = sqlx::query_scalar!(
+            r#"
+            INSERT INTO login_failures (key, failures, last_failure_at)
+            VALUES ($1, 1, NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                failures = CASE
+                    WHEN GREATEST(login_failures.last_failure_at, login_failures.locked_until)
+                         < NOW() - make_interval(secs => $2)
+                    THEN 1
+                    ELSE login_failures.failures + 1
+                END,
+                last_failure_at = NOW()
+            RETURNING failures
+            "#,
+            key,
+            self.failure_window_secs
+        )
+            .fetch_one(&self.pool)
+            .await?;
+
+        if failures < threshold {
+            return Ok(false);
+        }
+
+        let lockout = (self.base_lockout_secs * 2f64.powi(failures - threshold)).min(self.max_lockout_secs);
+        sqlx::query!(
+            "UPDATE login_failures SET locked_until = NOW() + make_interval(secs => $2) WHERE key = $1",
+            key,
+            lockout
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+}
+
+// Emails are compared case-insensitively, so are their counters
+fn account_key(email: &str) -> String {
+    format!("account:{}", email.to_lowercase())
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+fn keys(email: &str, ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![account_key(email)];
+    keys.extend(ip.map(ip_key));
+    keys
+}
@@ -1,16 +1,32 @@
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpResponse, http::header,
+    web, Error, HttpMessage, ResponseError, http::header::{self, HeaderMap},
     body::EitherBody,
 };
 use futures::future::{ready, LocalBoxFuture, Ready};
+use std::sync::Arc;
 use tracing::{error, info};
-use serde_json::json;
-use crate::auth::jwt::validate_token;
+use crate::auth::access::{required_access, Access, AccessRule};
+use crate::auth::jwt::{validate_token, TokenPurpose};
+use crate::auth::keys::JwtKeys;
+use crate::auth::principal::AuthenticatedUser;
+use crate::auth::revocation::RevocationStore;
+use crate::error::AppError;
 
-pub struct AuthMiddleware;
+// Enforces the access rules: public routes pass straight through, every
+// other route needs a valid session token, and role-restricted routes also
+// need one of the listed roles.
+#[derive(Clone)]
+pub struct AuthMiddleware {
+    rules: Arc<Vec<AccessRule>>,
+}
+
+impl AuthMiddleware {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        Self { rules: Arc::new(rules) }
+    }
+}
 
-// Transform implementation remains the same
 impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
@@ -24,12 +40,13 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthMiddlewareService { service }))
+        ready(Ok(AuthMiddlewareService { service, rules: self.rules.clone() }))
     }
 }
 
 pub struct AuthMiddlewareService<S> {
     service: S,
+    rules: Arc<Vec<AccessRule>>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
@@ -45,7 +62,10 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        if req.path() == "/signup" || req.path() == "/signin" {
+        // The path as the router sees it, with percent-encoding resolved, so
+        // `/user%73` cannot slip past the rule for `/users`
+        let access = required_access(&self.rules, req.method(), req.match_info().as_str()).clone();
+        if access == Access::Public {
             let fut = self.service.call(req);
             return Box::pin(async move {
                 let res = fut.await?;
@@ -53,45 +73,49 @@ where
             });
         }
 
-        let auth_header = req.headers().get(header::AUTHORIZATION);
-
-        let auth_token = match auth_header {
-            Some(header) => {
-                match header.to_str() {
-                    Ok(header_str) if header_str.starts_with("Bearer ") => {
-                        header_str[7..].to_string()
-                    },
-                    _ => {
-                        return Box::pin(async move {
-                            let (request, _) = req.into_parts();
-                            // Create the complete response first
-                            let error_response = HttpResponse::Unauthorized()
-                                .json(json!({ "error": "Invalid authorization header format" }));
-                            // Then create the ServiceResponse and map it
-                            Ok(ServiceResponse::new(
-                                request,
-                                error_response,
-                            ).map_into_right_body())
-                        });
-                    }
-                }
-            }
+        let auth_token = match bearer_token(req.headers()) {
+            Ok(token) => token.to_string(),
+            Err(e) => return reject(req, e),
+        };
+
+        let keys = match req.app_data::<web::Data<JwtKeys>>() {
+            Some(keys) => keys.clone(),
             None => {
-                return Box::pin(async move {
-                    let (request, _) = req.into_parts();
-                    let error_response = HttpResponse::Unauthorized()
-                        .json(json!({ "error": "Missing authorization header" }));
-                    Ok(ServiceResponse::new(
-                        request,
-                        error_response,
-                    ).map_into_right_body())
-                });
+                error!("JWT keys are not registered as app data");
+                return reject(req, AppError::Unauthorized("Invalid token".to_string()));
             }
         };
 
-        match validate_token(auth_token) {
+        match validate_token(&keys, auth_token, TokenPurpose::Session) {
             Ok(claims) => {
-                info!("Authenticated user: {}", claims.sub);
+                let revoked = req
+                    .app_data::<web::Data<RevocationStore>>()
+                    .is_some_and(|store| store.is_revoked(&claims));
+                if revoked {
+                    info!("Rejected revoked token {} for user: {}", claims.jti, claims.sub);
+                    return reject(req, AppError::TokenRevoked);
+                }
+
+                // Session tokens issued before they carried the user id
+                let Some(user) = AuthenticatedUser::from_claims(claims) else {
+                    return reject(req, AppError::Unauthorized("Invalid token".to_string()));
+                };
+
+                let allowed = match &access {
+                    Access::AnyRole(roles) => user.has_any_role(roles),
+                    Access::Permission(permission) => user.has_permission(permission),
+                    Access::Public | Access::Authenticated => true,
+                };
+                if !allowed {
+                    info!("User {} lacks {:?} for {} {}", user.uid, access, req.method(), req.path());
+                    return reject(req, AppError::Forbidden(
+                        "You are not allowed to access this resource".to_string(),
+                    ));
+                }
+
+                info!("Authenticated user: {}", user.uid);
+                // Handlers receive it through the `AuthenticatedUser` extractor
+                req.extensions_mut().insert(user);
                 let fut = self.service.call(req);
                 Box::pin(async move {
                     let res = fut.await?;
@@ -100,16 +124,32 @@ where
             }
             Err(e) => {
                 error!("Token validation failed: {:?}", e);
-                Box::pin(async move {
-                    let (request, _) = req.into_parts();
-                    let error_response = HttpResponse::Unauthorized()
-                        .json(json!({ "error": "Invalid token" }));
-                    Ok(ServiceResponse::new(
-                        request,
-                        error_response,
-                    ).map_into_right_body())
-                })
+                reject(req, AppError::from(e))
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// The token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Result<&str, AppError> {
+    let auth_header = headers
+        .get(header::AUTHORIZATION)
+        .ok_or_else(|| AppError::Unauthorized("Missing authorization header".to_string()))?;
+
+    auth_header
+        .to_str()
+        .ok()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("Invalid authorization header format".to_string()))
+}
+
+// Short-circuits the request with the JSON rendering of the given error
+fn reject<B: 'static>(
+    req: ServiceRequest,
+    err: AppError,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>> {
+    Box::pin(async move {
+        let (request, _) = req.into_parts();
+        Ok(ServiceResponse::new(request, err.error_response()).map_into_right_body())
+    })
+}
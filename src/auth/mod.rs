@@ -1,2 +1,13 @@
+pub mod access;
+pub mod bootstrap;
+pub mod client_ip;
+pub mod email_normalizer;
+pub mod hash_pool;
 pub mod jwt;
-pub mod middleware;
\ No newline at end of file
+pub mod keys;
+pub mod login_throttle;
+pub mod middleware;
+pub mod password_hash;
+pub mod password_policy;
+pub mod principal;
+pub mod revocation;
\ No newline at end of file
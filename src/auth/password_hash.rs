@@ -0,0 +1,107 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use std::sync::Arc;
+use std::thread;
+
+use crate::auth::hash_pool::{HashError, HashPool};
+use crate::config::settings::PasswordHashSettings;
+
+// Errors raised while loading the Argon2 parameters
+#[derive(Debug, thiserror::Error)]
+pub enum HashConfigError {
+    #[error("Invalid password hashing setting {0}: {1}")]
+    InvalidSetting(&'static str, String),
+}
+
+// Argon2id with the cost parameters from the `[password_hash]` settings.
+// Raising them only affects new hashes; older ones are upgraded on the next
+// successful login. The work runs on a `HashPool` sized by the same settings.
+#[derive(Clone)]
+pub struct Argon2Hasher {
+    params: Params,
+    pool: Arc<HashPool>,
+    // Verified against for unknown users so they cost as much as known ones
+    dummy_hash: Arc<str>,
+}
+
+impl Argon2Hasher {
+    pub fn new(settings: &PasswordHashSettings) -> Result<Self, HashConfigError> {
+        let params = settings
+            .params()
+            .map_err(|e| HashConfigError::InvalidSetting("password_hash", e.to_string()))?;
+
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+        let workers = settings.pool_threads.unwrap_or(cpus);
+        let queue_limit = settings.queue_limit.unwrap_or(workers * 16);
+
+        let pool = Arc::new(HashPool::new(workers as usize, queue_limit as usize));
+        let dummy_hash = Self::argon2(params.clone())
+            .hash_password(b"dummy password", &SaltString::generate(&mut OsRng))
+            .map_err(|e| HashConfigError::InvalidSetting("password_hash", e.to_string()))?
+            .to_string()
+            .into();
+        Ok(Self { params, pool, dummy_hash })
+    }
+
+    // The pool doing the work, for exposing its metrics
+    pub fn pool(&self) -> Arc<HashPool> {
+        self.pool.clone()
+    }
+
+    fn argon2(params: Params) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, HashError> {
+        let params = self.params.clone();
+        let password = password.to_string();
+
+        self.pool
+            .run(move || {
+                let salt = SaltString::generate(&mut OsRng);
+                Self::argon2(params)
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|e| HashError::Failed(e.to_string()))
+            })
+            .await?
+    }
+
+    // Verifies against the parameters stored in the hash itself, so hashes
+    // made with older settings keep working
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<bool, HashError> {
+        let params = self.params.clone();
+        let password = password.to_string();
+        let hash = hash.to_string();
+
+        self.pool
+            .run(move || {
+                let parsed = PasswordHash::new(&hash).map_err(|e| HashError::Failed(e.to_string()))?;
+                Ok(Self::argon2(params).verify_password(password.as_bytes(), &parsed).is_ok())
+            })
+            .await?
+    }
+
+    // Does the same work as `verify` without a real hash to check against
+    pub async fn verify_dummy(&self, password: &str) -> Result<(), HashError> {
+        let dummy_hash = self.dummy_hash.clone();
+        self.verify(password, &dummy_hash).await.map(|_| ())
+    }
+
+    // Whether the hash was made with a different variant or version, or is
+    // cheaper than the current parameters
+    pub fn needs_rehash(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+        match Params::try_from(hash) {
+            Ok(stored) => {
+                stored.m_cost() < self.params.m_cost()
+                    || stored.t_cost() < self.params.t_cost()
+                    || stored.p_cost() < self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+use tracing::error;
+
+use crate::config::settings::PasswordPolicySettings;
+use crate::error::{AppError, FieldError};
+
+// Passwords that show up at the top of every leaked password list. Matches
+// against these (and the user's own email) are scored by their rank instead
+// of as random characters.
+const COMMON_WORDS: &[&str] = &[
+    "password", "123456", "qwerty", "letmein", "welcome", "admin", "login",
+    "secret", "dragon", "monkey", "football", "baseball", "master", "shadow",
+    "sunshine", "princess", "iloveyou", "trustno1", "starwars", "whatever",
+    "superman", "batman", "michael", "charlie", "jordan", "hunter", "ranger",
+    "killer", "soccer", "hockey", "freedom", "summer", "winter", "spring",
+    "autumn", "flower", "cookie", "pepper", "ginger", "chocolate", "orange",
+    "banana", "cheese", "computer", "internet", "access", "pass", "test",
+    "user", "root", "love", "hello", "abc", "qwe", "asd", "zxc", "azerty",
+    "qazwsx", "changeme", "default", "guest", "mustang", "harley", "matrix",
+    "tigger", "buster", "thomas", "robert", "daniel", "jessica", "ashley",
+];
+
+// Rules a new password must satisfy, from the `[password_policy]` settings
+pub struct PasswordPolicy {
+    min_length: usize,
+    // Bounds the Argon2 input so huge passwords cannot be used to burn CPU
+    max_length: usize,
+    // How many of lowercase, uppercase, digits and symbols must be present
+    min_char_classes: usize,
+    // Minimum strength score from 0 (trivial) to 4 (very strong)
+    min_score: u8,
+    // Directory of range files named by the first five hex characters of the
+    // SHA-1 hash, each line holding the remaining suffix and a count, as
+    // produced by the Pwned Passwords downloader
+    breach_dir: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    pub fn new(settings: &PasswordPolicySettings) -> Self {
+        Self {
+            min_length: settings.min_length,
+            max_length: settings.max_length,
+            min_char_classes: settings.min_char_classes,
+            min_score: settings.min_score,
+            breach_dir: settings.breach_dir.clone(),
+        }
+    }
+
+    // Checks `password` against the policy, reporting every violation for
+    // `field` at once. `user_inputs` (e.g. the email address) are treated as
+    // guessable words.
+    pub async fn validate(&self, field: &str, password: &str, user_inputs: &[&str]) -> Result<(), AppError> {
+        let mut errors = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            errors.push(FieldError::new(
+                field,
+                "too_short",
+                format!("Must be at least {} characters long", self.min_length),
+            ));
+        }
+        if length > self.max_length {
+            errors.push(FieldError::new(
+                field,
+                "too_long",
+                format!("Must be at most {} characters long", self.max_length),
+            ));
+            // Don't spend any more effort on oversized input
+            return Err(AppError::Validation(errors));
+        }
+        if char_classes(password) < self.min_char_classes {
+            errors.push(FieldError::new(
+                field,
+                "missing_character_classes",
+                format!(
+                    "Must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                    self.min_char_classes
+                ),
+            ));
+        }
+        if strength_score(password, user_inputs) < self.min_score {
+            errors.push(FieldError::new(
+                field,
+                "too_weak",
+                "Too easy to guess, try a longer password or a few unrelated words".to_string(),
+            ));
+        }
+        if self.is_breached(password).await {
+            errors.push(FieldError::new(
+                field,
+                "breached",
+                "Appears in a known data breach, choose a different password".to_string(),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(errors))
+        }
+    }
+
+    // Looks the password up in the local breach corpus. Only the range file
+    // for the first five hash characters is read. An unreadable corpus is
+    // logged and does not block the user.
+    async fn is_breached(&self, password: &str) -> bool {
+        let Some(dir) = &self.breach_dir else {
+            return false;
+        };
+
+        let hash: String = Sha1::digest(password.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let (prefix, suffix) = hash.split_at(5);
+
+        let path = dir.join(format!("{}.txt", prefix));
+        match tokio::fs::read_to_string(&path).await {
+            Ok(range) => range.lines().any(|line| {
+                line.split(':')
+                    .next()
+                    .is_some_and(|candidate| candidate.trim().eq_ignore_ascii_case(suffix))
+            }),
+            // Ranges with no breached hashes may be left out of the corpus
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => {
+                error!("Failed to read breached password range {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+}
+
+fn char_classes(password: &str) -> usize {
+    [
+        password.chars().any(char::is_lowercase),
+        password.chars().any(char::is_uppercase),
+        password.chars().any(char::is_numeric),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .iter()
+    .filter(|present| **present)
+    .count()
+}
+
+// Estimates how many guesses an attacker needs, in the spirit of zxcvbn: the
+// password is split greedily into common words, repeated characters,
+// sequences and leftover characters, each costing a number of guesses. The
+// log10 of the total maps onto zxcvbn's 0-4 score.
+fn strength_score(password: &str, user_inputs: &[&str]) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    // Lowercased char by char, `str::to_lowercase` can change the length
+    // (`İ` becomes two chars) and both vectors are indexed alike
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+
+    // The user's own inputs are the first thing an attacker tries
+    let mut words: Vec<Vec<char>> = user_inputs
+        .iter()
+        .flat_map(|input| input.split(|c: char| !c.is_alphanumeric()))
+        .filter(|part| part.chars().count() >= 3)
+        .map(|part| part.to_lowercase().chars().collect())
+        .collect();
+    words.extend(COMMON_WORDS.iter().map(|word| word.chars().collect::<Vec<char>>()));
+
+    let mut log10_guesses = 0.0;
+    let mut i = 0;
+    while i < chars.len() {
+        let (len, guesses) = longest_word(&lower[i..], &words)
+            .map(|(len, rank)| {
+                // Capitalisation adds little on top of the word itself
+                let capitalised = chars[i..i + len].iter().any(|c| c.is_uppercase());
+                (len, rank as f64 * if capitalised { 2.0 } else { 1.0 })
+            })
+            .or_else(|| repeat_run(&chars[i..]).map(|len| (len, cardinality(chars[i]) * len as f64)))
+            .or_else(|| sequence_run(&lower[i..]).map(|len| (len, 4.0 * len as f64)))
+            .unwrap_or((1, cardinality(chars[i])));
+
+        log10_guesses += guesses.log10();
+        i += len;
+    }
+
+    match log10_guesses {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+// Longest word starting the input, with its rank in the word list (1-based)
+fn longest_word(input: &[char], words: &[Vec<char>]) -> Option<(usize, usize)> {
+    words
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| input.starts_with(word))
+        .max_by_key(|(rank, word)| (word.len(), std::cmp::Reverse(*rank)))
+        .map(|(rank, word)| (word.len(), rank + 1))
+}
+
+// Length of a run of the same character, if at least three long
+fn repeat_run(input: &[char]) -> Option<usize> {
+    let len = input.iter().take_while(|c| **c == input[0]).count();
+    (len >= 3).then_some(len)
+}
+
+// Length of an ascending or descending run like "abc" or "987", if at least
+// three long
+fn sequence_run(input: &[char]) -> Option<usize> {
+    if input.len() < 3 {
+        return None;
+    }
+    let step = input[1] as i64 - input[0] as i64;
+    if step.abs() != 1 {
+        return None;
+    }
+    let len = 1 + input
+        .windows(2)
+        .take_while(|pair| pair[1] as i64 - pair[0] as i64 == step)
+        .count();
+    (len >= 3).then_some(len)
+}
+
+// Guesses needed to brute force a single character of this kind
+fn cardinality(c: char) -> f64 {
+    if c.is_ascii_digit() {
+        10.0
+    } else if c.is_ascii_lowercase() || c.is_ascii_uppercase() {
+        26.0
+    } else if c.is_ascii() {
+        33.0
+    } else {
+        100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_common_words_low() {
+        assert_eq!(strength_score("password", &[]), 0);
+        assert_eq!(strength_score("alicewonderland", &["alicewonderland@example.com"]), 0);
+        assert_eq!(strength_score("alicewonderland", &[]), 4);
+    }
+
+    #[test]
+    fn scores_non_ascii_passwords_whose_lowercase_is_longer() {
+        // `İ` lowercases to two chars, which used to shift the word matches
+        // past the end of the password
+        assert_eq!(strength_score("İİİİcorrectpassword", &[]), 4);
+        assert!(strength_score("PASSWORDİ", &[]) < strength_score("xq7#Lm2!vR9@", &[]));
+    }
+}
@@ -0,0 +1,64 @@
+use actix_web::{dev::Payload, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+use uuid::Uuid;
+
+use crate::auth::jwt::Claims;
+use crate::error::AppError;
+
+// The user behind a request, inserted into the request extensions by
+// `AuthMiddleware` once the session token checks out. Handlers take it as an
+// argument instead of looking at the token themselves.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub uid: Uuid,
+    pub email: String,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    // Id and expiry of the session token, for revoking it
+    pub token_id: Uuid,
+    pub token_expires_at: i64,
+    // Scopes the token is narrowed to, empty means unrestricted. Session
+    // tokens are issued without scopes for now, so nothing checks them yet.
+    #[allow(dead_code)]
+    pub scopes: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    // `None` for tokens that don't identify a user, i.e. anything but a
+    // session token
+    pub fn from_claims(claims: Claims) -> Option<Self> {
+        Some(Self {
+            uid: claims.uid?,
+            email: claims.sub,
+            roles: claims.roles,
+            permissions: claims.permissions,
+            token_id: claims.jti,
+            token_expires_at: claims.exp,
+            scopes: claims.scopes,
+        })
+    }
+
+    pub fn has_any_role(&self, roles: &[&str]) -> bool {
+        self.roles.iter().any(|role| roles.contains(&role.as_str()))
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    // Only fails on public routes, which the middleware lets through without
+    // a session
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| AppError::Unauthorized("Missing authentication".to_string())),
+        )
+    }
+}
@@ -0,0 +1,124 @@
+use chrono::Utc;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::auth::jwt::Claims;
+
+#[derive(Default)]
+struct RevocationCache {
+    // jti -> token expiry (unix seconds)
+    tokens: HashMap<Uuid, i64>,
+    // subject -> tokens issued before this instant (unix seconds) are revoked
+    subjects: HashMap<String, i64>,
+}
+
+// Revoked access tokens. Postgres is the source of truth; the in-memory copy
+// is what `AuthMiddleware` consults on every request. It is updated directly
+// on revocation and re-synced periodically to pick up revocations made by
+// other instances.
+pub struct RevocationStore {
+    pool: PgPool,
+    cache: RwLock<RevocationCache>,
+}
+
+impl RevocationStore {
+    pub async fn load(pool: PgPool) -> Result<Self, sqlx::Error> {
+        let store = Self { pool, cache: RwLock::new(RevocationCache::default()) };
+        store.sync().await?;
+        Ok(store)
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        let cache = self.cache.read().unwrap();
+        cache.tokens.contains_key(&claims.jti)
+            || cache
+                .subjects
+                .get(&claims.sub)
+                .is_some_and(|revoked_before| claims.iat < *revoked_before)
+    }
+
+    // Revokes a single token until it expires
+    pub async fn revoke_token(&self, jti: Uuid, exp: i64) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO revoked_tokens (jti, expires_at)
+            VALUES ($1, to_timestamp($2))
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+            jti,
+            exp as f64
+        )
+            .execute(&self.pool)
+            .await?;
+
+        self.cache.write().unwrap().tokens.insert(jti, exp);
+        Ok(())
+    }
+
+    // Revokes every token issued to the subject before the current second.
+    // `iat` has one second resolution, so tokens issued within this very
+    // second stay valid; callers revoke their own token by `jti` as well.
+    pub async fn revoke_subject(&self, subject: &str) -> Result<(), sqlx::Error> {
+        let revoked_before = Utc::now().timestamp();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO subject_revocations (subject, revoked_before)
+            VALUES ($1, to_timestamp($2))
+            ON CONFLICT (subject) DO UPDATE SET revoked_before = EXCLUDED.revoked_before
+            "#,
+            subject,
+            revoked_before as f64
+        )
+            .execute(&self.pool)
+            .await?;
+
+        self.cache.write().unwrap().subjects.insert(subject.to_string(), revoked_before);
+        Ok(())
+    }
+
+    // Reloads the cache from the database, dropping revocations of tokens
+    // that have expired in the meantime
+    pub async fn sync(&self) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(&self.pool)
+            .await?;
+
+        let mut tokens: HashMap<Uuid, i64> = sqlx::query!(
+            r#"SELECT jti, EXTRACT(EPOCH FROM expires_at)::BIGINT AS "exp!" FROM revoked_tokens"#
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.jti, row.exp))
+            .collect();
+
+        let mut subjects: HashMap<String, i64> = sqlx::query!(
+            r#"SELECT subject, EXTRACT(EPOCH FROM revoked_before)::BIGINT AS "revoked_before!" FROM subject_revocations"#
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.subject, row.revoked_before))
+            .collect();
+
+        // Keep revocations recorded locally while the queries were running
+        let now = Utc::now().timestamp();
+        let mut cache = self.cache.write().unwrap();
+        tokens.extend(cache.tokens.iter().filter(|(_, exp)| **exp > now).map(|(jti, exp)| (*jti, *exp)));
+        for (subject, revoked_before) in cache.subjects.drain() {
+            let entry = subjects.entry(subject).or_insert(revoked_before);
+            *entry = (*entry).max(revoked_before);
+        }
+        *cache = RevocationCache { tokens, subjects };
+        debug!(
+            "Revocation cache synced: {} token(s), {} subject(s)",
+            cache.tokens.len(),
+            cache.subjects.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::auth::email_normalizer::EmailNormalizer;
+use crate::config;
+use crate::config::settings::Settings;
+
+// Exit code when some addresses could not be normalized automatically
+const EXIT_NEEDS_ATTENTION: i32 = 3;
+
+// Rewrites stored email addresses into their normalized form.
+//
+// Only reports by default; `--apply` writes the changes in one transaction.
+// Addresses that would end up belonging to the same account are collisions
+// and are left alone for someone to merge by hand, as are addresses that are
+// not valid at all. Running it again is harmless.
+//
+// Prints one tab-separated line per finding to stdout:
+//   update     <uid>  <old email>  <new email>
+//   collision  <normalized email>  <uid>=<email> ...
+//   invalid    <uid>  <email>  <reason>
+// and exits with 3 if there were collisions or invalid addresses.
+pub async fn run(args: &[String], settings: &Settings) -> i32 {
+    let apply = match args {
+        [] => false,
+        [flag] if flag == "--apply" => true,
+        _ => {
+            eprintln!("Usage: first-backend backfill-emails [--apply]");
+            return 2;
+        }
+    };
+
+    let normalizer = EmailNormalizer::new(settings.email.provider_rules);
+    if let Err(e) = settings.require_database() {
+        eprintln!("{}", e);
+        return 1;
+    }
+    let pool = match config::database::create_pool(&settings.database).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return 1;
+        }
+    };
+
+    match backfill(&pool, &normalizer, apply).await {
+        Ok(report) => {
+            eprintln!(
+                "{} address(es) {}, {} collision(s), {} invalid",
+                report.updated,
+                if apply { "updated" } else { "to update, pass --apply to write them" },
+                report.collisions,
+                report.invalid
+            );
+            if report.collisions > 0 || report.invalid > 0 {
+                EXIT_NEEDS_ATTENTION
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            eprintln!("Backfill failed, nothing was changed: {}", e);
+            1
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    updated: usize,
+    collisions: usize,
+    invalid: usize,
+}
+
+async fn backfill(pool: &PgPool, normalizer: &EmailNormalizer, apply: bool) -> Result<Report, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut report = Report::default();
+
+    // Lock the table so no signup sneaks in between reading and writing
+    sqlx::query!("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let users = sqlx::query!("SELECT uid, email FROM users ORDER BY created_at, uid")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    // Accounts by the address they normalize to, compared like the database does
+    let mut accounts: BTreeMap<String, Vec<(Uuid, String, String)>> = BTreeMap::new();
+    for user in users {
+        match normalizer.try_normalize(&user.email) {
+            Ok(normalized) => accounts
+                .entry(normalized.to_lowercase())
+                .or_default()
+                .push((user.uid, user.email, normalized)),
+            Err(reason) => {
+                println!("invalid\t{}\t{}\t{}", user.uid, user.email, reason);
+                report.invalid += 1;
+            }
+        }
+    }
+
+    for (key, group) in accounts {
+        if group.len() > 1 {
+            let owners: Vec<String> = group.iter().map(|(uid, email, _)| format!("{}={}", uid, email)).collect();
+            println!("collision\t{}\t{}", key, owners.join("\t"));
+            report.collisions += 1;
+            continue;
+        }
+
+        let (uid, email, normalized) = &group[0];
+        if email == normalized {
+            continue;
+        }
+        println!("update\t{}\t{}\t{}", uid, email, normalized);
+        report.updated += 1;
+        if apply {
+            sqlx::query!("UPDATE users SET email = $2 WHERE uid = $1", uid, normalized)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    if apply {
+        tx.commit().await?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,101 @@
+use sqlx::PgPool;
+
+use crate::config;
+use crate::config::database::{MigrationState, MIGRATOR};
+use crate::config::settings::Settings;
+
+const USAGE: &str = "Usage: first-backend migrate up|down [<version>]|status";
+
+// Exit code of `status` when the database is not up to date
+const EXIT_NOT_CURRENT: i32 = 3;
+
+// Applies or reverts the embedded migrations, or reports where the database
+// stands:
+//   migrate up                apply every pending migration
+//   migrate down [<version>]  revert everything after <version>, by default
+//                             just the latest migration
+//   migrate status            one tab-separated line per migration, exits
+//                             with 3 if any is pending or was modified
+pub async fn run(args: &[String], settings: &Settings) -> i32 {
+    if let Err(e) = settings.require_database() {
+        eprintln!("{}", e);
+        return 1;
+    }
+    let pool = match config::database::create_pool(&settings.database).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return 1;
+        }
+    };
+
+    let result = match args {
+        [command] if command == "up" => up(&pool).await,
+        [command] if command == "down" => down(&pool, None).await,
+        [command, version] if command == "down" => match version.parse() {
+            Ok(version) => down(&pool, Some(version)).await,
+            Err(_) => Err(format!("{:?} is not a migration version", version)),
+        },
+        [command] if command == "status" => status(&pool).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            1
+        }
+    }
+}
+
+async fn up(pool: &PgPool) -> Result<i32, String> {
+    MIGRATOR.run(pool).await.map_err(|e| e.to_string())?;
+    eprintln!("Database is up to date");
+    Ok(0)
+}
+
+async fn down(pool: &PgPool, target: Option<i64>) -> Result<i32, String> {
+    let statuses = config::database::migration_status(pool).await.map_err(|e| e.to_string())?;
+    let applied: Vec<i64> = statuses
+        .iter()
+        .filter(|status| status.state != MigrationState::Pending)
+        .map(|status| status.version)
+        .collect();
+
+    let target = match target {
+        Some(target) => target,
+        // The version before the latest applied one, 0 reverts everything
+        None => match applied.as_slice() {
+            [] => {
+                eprintln!("No migrations to revert");
+                return Ok(0);
+            }
+            [.., previous, _] => *previous,
+            [_] => 0,
+        },
+    };
+
+    MIGRATOR.undo(pool, target).await.map_err(|e| e.to_string())?;
+    eprintln!("Reverted to version {}", target);
+    Ok(0)
+}
+
+async fn status(pool: &PgPool) -> Result<i32, String> {
+    let statuses = config::database::migration_status(pool).await.map_err(|e| e.to_string())?;
+
+    let mut current = true;
+    for status in statuses {
+        let state = match status.state {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::Modified => "modified",
+        };
+        current &= status.state == MigrationState::Applied;
+        println!("{}\t{}\t{}", status.version, status.description, state);
+    }
+    Ok(if current { 0 } else { EXIT_NOT_CURRENT })
+}
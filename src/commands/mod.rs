@@ -0,0 +1,19 @@
+pub mod backfill_emails;
+pub mod migrate;
+
+use crate::config::settings::Settings;
+
+const USAGE: &str = "Usage: first-backend [migrate up|down [<version>]|status | backfill-emails [--apply]]";
+
+// Maintenance commands, run instead of the server when the binary is given
+// arguments. Returns the process exit code.
+pub async fn run(command: &str, args: &[String], settings: &Settings) -> i32 {
+    match command {
+        "migrate" => migrate::run(args, settings).await,
+        "backfill-emails" => backfill_emails::run(args, settings).await,
+        other => {
+            eprintln!("Unknown command: {}\n{}", other, USAGE);
+            2
+        }
+    }
+}
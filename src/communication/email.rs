@@ -2,48 +2,49 @@
 
 use sendgrid::v3::{Content, Email, Message, Personalization, Sender};
 use reqwest::Client;
-use std::env;
+
+use crate::config::settings::EmailSettings;
 
 // We create a dedicated error type for email-related operations
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum EmailError {
-    EnvVarMissing(String),
+    #[error("SendGrid error: {0}")]
     SendGridError(String),
-    ConfigError(String),
 }
 
-impl std::fmt::Display for EmailError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            EmailError::EnvVarMissing(var) => write!(f, "Missing environment variable: {}", var),
-            EmailError::SendGridError(err) => write!(f, "SendGrid error: {}", err),
-            EmailError::ConfigError(err) => write!(f, "Configuration error: {}", err),
-        }
-    }
+// Sends the service's emails through SendGrid with the `[email]` settings.
+// Built once at startup so every send reuses the same HTTP client.
+pub struct Mailer {
+    sender: Sender,
+    sender_email: String,
+    frontend_url: String,
+    configured: bool,
 }
 
-impl std::error::Error for EmailError {}
-
-pub async fn send_verification_email(to_email: &str, token: &str) -> Result<(), EmailError> {
-    // Retrieve necessary environment variables
-    let sendgrid_api_key = env::var("SENDGRID_API_KEY")
-        .map_err(|_| EmailError::EnvVarMissing("SENDGRID_API_KEY".to_string()))?;
-
-    let frontend_url = env::var("FRONTEND_URL")
-        .map_err(|_| EmailError::EnvVarMissing("FRONTEND_URL".to_string()))?;
-
-    let sender_email = env::var("SENDER_EMAIL")
-        .map_err(|_| EmailError::EnvVarMissing("SENDER_EMAIL".to_string()))?;
+impl Mailer {
+    pub fn new(settings: &EmailSettings) -> Self {
+        Self {
+            sender: Sender::new(settings.sendgrid_api_key.clone(), Some(Client::new())),
+            sender_email: settings.sender_email.clone(),
+            frontend_url: settings.frontend_url.clone(),
+            configured: [&settings.sendgrid_api_key, &settings.sender_email, &settings.frontend_url]
+                .iter()
+                .all(|value| !value.trim().is_empty()),
+        }
+    }
 
-    // Create a default HTTP client
-    let client = Client::new();
+    // Whether everything needed to send emails is set
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
 
-    // Create the verification URL with the token
-    let verification_url = format!("{}/verify?token={}", frontend_url, token);
+    pub async fn send_verification_email(&self, to_email: &str, token: &str) -> Result<(), EmailError> {
+        // Create the verification URL with the token
+        let verification_url = format!("{}/verify?token={}", self.frontend_url, token);
 
-    // Create HTML content for the email
-    let html_content = format!(
-        r#"
+        // Create HTML content for the email
+        let html_content = format!(
+            r#"
         <!DOCTYPE html>
         <html>
         <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
@@ -69,30 +70,139 @@ pub async fn send_verification_email(to_email: &str, token: &str) -> Result<(),
         </body>
         </html>
         "#,
-        verification_url,
-        verification_url
-    );
-
-    // Create the SendGrid message
-    let personalization = Personalization::new(Email::new(to_email));
-
-    let message = Message::new(Email::new(&sender_email))
-        .set_subject("Verify Your Email Address")
-        .add_content(
-            Content::new()
-                .set_content_type("text/html")
-                .set_value(&html_content)
-        )
-        .add_personalization(personalization);
-
-    // Create the SendGrid sender
-    let sender = Sender::new(sendgrid_api_key, Some(client));
-
-    // Send the email
-    sender
-        .send(&message)
-        .await
-        .map_err(|e| EmailError::SendGridError(e.to_string()))?;
-
-    Ok(())
-}
\ No newline at end of file
+            verification_url,
+            verification_url
+        );
+
+        self.send_email(to_email, "Verify Your Email Address", &html_content).await
+    }
+
+    // Sent instead of a verification email when someone signs up with an email
+    // that already has an account
+    pub async fn send_account_exists_email(&self, to_email: &str) -> Result<(), EmailError> {
+        let signin_url = format!("{}/signin", self.frontend_url);
+        let forgot_url = format!("{}/forgot-password", self.frontend_url);
+
+        // Create HTML content for the email
+        let html_content = format!(
+            r#"
+        <!DOCTYPE html>
+        <html>
+        <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
+            <div style="max-width: 600px; margin: 0 auto; padding: 20px;">
+                <h2>You Already Have an Account</h2>
+                <p>Someone tried to sign up with this email address, but it already has an account.</p>
+                <p>If it was you, you can <a href="{}">sign in</a> or <a href="{}">reset your password</a> if you forgot it.</p>
+                <p>If it wasn't you, you can safely ignore this email.</p>
+            </div>
+        </body>
+        </html>
+        "#,
+            signin_url,
+            forgot_url
+        );
+
+        self.send_email(to_email, "You Already Have an Account", &html_content).await
+    }
+
+    pub async fn send_password_reset_email(&self, to_email: &str, token: &str) -> Result<(), EmailError> {
+        // Create the reset URL with the token
+        let reset_url = format!("{}/reset-password?token={}", self.frontend_url, token);
+
+        // Create HTML content for the email
+        let html_content = format!(
+            r#"
+        <!DOCTYPE html>
+        <html>
+        <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
+            <div style="max-width: 600px; margin: 0 auto; padding: 20px;">
+                <h2>Reset Your Password</h2>
+                <p>We received a request to reset your password. Click the button below to choose a new one:</p>
+                <div style="text-align: center; margin: 30px 0;">
+                    <a href="{}"
+                       style="background-color: #4CAF50;
+                              color: white;
+                              padding: 12px 24px;
+                              text-decoration: none;
+                              border-radius: 4px;
+                              display: inline-block;">
+                        Reset Password
+                    </a>
+                </div>
+                <p>If the button doesn't work, you can copy and paste this link into your browser:</p>
+                <p style="word-break: break-all;">{}</p>
+                <p>This link will expire in 1 hour. Resetting your password signs you out everywhere.</p>
+                <p>If you didn't request a password reset, please ignore this email.</p>
+            </div>
+        </body>
+        </html>
+        "#,
+            reset_url,
+            reset_url
+        );
+
+        self.send_email(to_email, "Reset Your Password", &html_content).await
+    }
+
+    pub async fn send_account_unlock_email(&self, to_email: &str, token: &str) -> Result<(), EmailError> {
+        // Create the unlock URL with the token
+        let unlock_url = format!("{}/unlock-account?token={}", self.frontend_url, token);
+
+        // Create HTML content for the email
+        let html_content = format!(
+            r#"
+        <!DOCTYPE html>
+        <html>
+        <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
+            <div style="max-width: 600px; margin: 0 auto; padding: 20px;">
+                <h2>Your Account Has Been Locked</h2>
+                <p>We locked your account after several failed sign-in attempts. If this was you, click the button below to unlock it right away:</p>
+                <div style="text-align: center; margin: 30px 0;">
+                    <a href="{}"
+                       style="background-color: #4CAF50;
+                              color: white;
+                              padding: 12px 24px;
+                              text-decoration: none;
+                              border-radius: 4px;
+                              display: inline-block;">
+                        Unlock Account
+                    </a>
+                </div>
+                <p>If the button doesn't work, you can copy and paste this link into your browser:</p>
+                <p style="word-break: break-all;">{}</p>
+                <p>This link will expire in 1 hour. Otherwise the lock is lifted automatically after a while.</p>
+                <p>If this wasn't you, someone may be trying to guess your password. Consider resetting it.</p>
+            </div>
+        </body>
+        </html>
+        "#,
+            unlock_url,
+            unlock_url
+        );
+
+        self.send_email(to_email, "Your Account Has Been Locked", &html_content).await
+    }
+
+    // Sends an HTML email through SendGrid
+    async fn send_email(&self, to_email: &str, subject: &str, html_content: &str) -> Result<(), EmailError> {
+        // Create the SendGrid message
+        let personalization = Personalization::new(Email::new(to_email));
+
+        let message = Message::new(Email::new(&self.sender_email))
+            .set_subject(subject)
+            .add_content(
+                Content::new()
+                    .set_content_type("text/html")
+                    .set_value(html_content)
+            )
+            .add_personalization(personalization);
+
+        // Send the email
+        self.sender
+            .send(&message)
+            .await
+            .map_err(|e| EmailError::SendGridError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+use argon2::Params;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::auth::client_ip::TrustedProxy;
+use crate::auth::jwt::TokenPurpose;
+
+// Where settings are read from unless `CONFIG_FILE` points elsewhere. The
+// file is optional, everything can come from the environment instead.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// Shortest accepted HS256 secret, as long as the SHA-256 output
+const MIN_HS256_SECRET_LEN: usize = 32;
+
+// Errors raised while loading the settings. Lists every problem at once so a
+// broken deployment can be fixed in one go.
+#[derive(Debug)]
+pub struct SettingsError(Vec<String>);
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+// Every setting of the service. Loaded once at startup from the TOML file,
+// then overridden by environment variables (named next to each field), then
+// validated as a whole. See `config.example.toml` for a documented example.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub email: EmailSettings,
+    pub jwt: JwtSettings,
+    pub password_hash: PasswordHashSettings,
+    pub password_policy: PasswordPolicySettings,
+    pub login_throttle: LoginThrottleSettings,
+    pub rate_limit: RateLimitSettings,
+    pub admin: AdminSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    // SERVER_HOST
+    pub host: String,
+    // SERVER_PORT
+    pub port: u16,
+    // SERVER_WORKERS, one per CPU when unset
+    pub workers: Option<usize>,
+    // TRUSTED_PROXIES, comma separated addresses or CIDR ranges of the
+    // reverse proxies in front of the server. Their `Forwarded` and
+    // `X-Forwarded-For` headers name the client; nobody else's are believed.
+    pub trusted_proxies: Vec<TrustedProxy>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self { host: "127.0.0.1".to_string(), port: 8080, workers: None, trusted_proxies: Vec::new() }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    // DATABASE_URL, required
+    pub url: String,
+    // DATABASE_MAX_CONNECTIONS
+    pub max_connections: u32,
+    // DATABASE_ACQUIRE_TIMEOUT_SECS
+    pub acquire_timeout_secs: u64,
+    // DATABASE_IDLE_TIMEOUT_SECS
+    pub idle_timeout_secs: u64,
+    // DATABASE_MAX_LIFETIME_SECS
+    pub max_lifetime_secs: u64,
+    // MIGRATE_ON_STARTUP, otherwise deployments run `migrate up` themselves
+    pub migrate_on_startup: bool,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 20,
+            acquire_timeout_secs: 3,
+            idle_timeout_secs: 600,
+            max_lifetime_secs: 1800,
+            migrate_on_startup: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailSettings {
+    // SENDGRID_API_KEY, required
+    pub sendgrid_api_key: String,
+    // SENDER_EMAIL, required
+    pub sender_email: String,
+    // FRONTEND_URL, required; the links in emails point there
+    pub frontend_url: String,
+    // EMAIL_PROVIDER_RULES, collapse aliases like Gmail dots and plus tags
+    pub provider_rules: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtSettings {
+    // JWT_ALGORITHM: HS256, RS256, ES256 or EdDSA
+    pub algorithm: String,
+    // JWT_SECRET, the HS256 shared secret
+    pub secret: Option<String>,
+    // JWT_SECRET_FILE, file holding the HS256 secret instead
+    pub secret_file: Option<PathBuf>,
+    // JWT_PRIVATE_KEY_FILE, PEM private key for RS256/ES256/EdDSA
+    pub private_key_file: Option<PathBuf>,
+    // JWT_KEY_ID, defaults to the key's JWK thumbprint
+    pub key_id: Option<String>,
+    // JWT_VERIFICATION_KEY_FILES, comma separated: retired keys still
+    // accepted, PEM public keys or secret files for HS256
+    pub verification_key_files: Vec<PathBuf>,
+    // JWT_MAX_VERIFICATION_KEYS, retired keys kept after rotation
+    pub max_verification_keys: usize,
+    // JWT_ROTATION_INTERVAL_SECS, rotate the signing key on this schedule,
+    // ES256 and EdDSA only. Generated keys are stored in the database and
+    // shared by every instance.
+    pub rotation_interval_secs: Option<u64>,
+}
+
+impl Default for JwtSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: "HS256".to_string(),
+            secret: None,
+            secret_file: None,
+            private_key_file: None,
+            key_id: None,
+            verification_key_files: Vec::new(),
+            max_verification_keys: 3,
+            rotation_interval_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordHashSettings {
+    // ARGON2_MEMORY_KIB
+    pub memory_kib: u32,
+    // ARGON2_ITERATIONS
+    pub iterations: u32,
+    // ARGON2_PARALLELISM
+    pub parallelism: u32,
+    // HASH_POOL_THREADS, one per CPU when unset
+    pub pool_threads: Option<u32>,
+    // HASH_POOL_QUEUE_LIMIT, 16 per thread when unset
+    pub queue_limit: Option<u32>,
+}
+
+impl Default for PasswordHashSettings {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            pool_threads: None,
+            queue_limit: None,
+        }
+    }
+}
+
+impl PasswordHashSettings {
+    pub fn params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicySettings {
+    // PASSWORD_MIN_LENGTH
+    pub min_length: usize,
+    // PASSWORD_MAX_LENGTH
+    pub max_length: usize,
+    // PASSWORD_MIN_CHAR_CLASSES, of lowercase, uppercase, digits and symbols
+    pub min_char_classes: usize,
+    // PASSWORD_MIN_SCORE, from 0 (trivial) to 4 (very strong)
+    pub min_score: u8,
+    // PASSWORD_BREACH_DIR, Pwned Passwords range files
+    pub breach_dir: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicySettings {
+    fn default() -> Self {
+        Self { min_length: 10, max_length: 128, min_char_classes: 1, min_score: 2, breach_dir: None }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginThrottleSettings {
+    // LOGIN_MAX_ACCOUNT_FAILURES
+    pub max_account_failures: i32,
+    // LOGIN_MAX_IP_FAILURES
+    pub max_ip_failures: i32,
+    // LOGIN_LOCKOUT_SECS
+    pub lockout_secs: f64,
+    // LOGIN_MAX_LOCKOUT_SECS
+    pub max_lockout_secs: f64,
+    // LOGIN_FAILURE_WINDOW_SECS
+    pub failure_window_secs: f64,
+}
+
+impl Default for LoginThrottleSettings {
+    fn default() -> Self {
+        Self {
+            max_account_failures: 5,
+            max_ip_failures: 20,
+            lockout_secs: 60.0,
+            max_lockout_secs: 3600.0,
+            failure_window_secs: 900.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    // RATE_LIMIT_BACKEND
+    pub backend: RateLimitBackendKind,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackendKind {
+    // Counters per instance
+    #[default]
+    Memory,
+    // Counters shared between instances
+    Postgres,
+}
+
+impl FromStr for RateLimitBackendKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(Self::Memory),
+            "postgres" => Ok(Self::Postgres),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    // ADMIN_EMAIL, the account given the admin role at startup
+    pub email: Option<String>,
+    // ADMIN_PASSWORD, only needed to create that account
+    pub password: Option<String>,
+}
+
+impl Settings {
+    // Reads the config file, applies the environment and validates everything
+    // the server needs
+    pub fn load() -> Result<Self, SettingsError> {
+        let mut settings = Self::read()?;
+
+        let mut problems = Vec::new();
+        settings.validate(&mut problems);
+        settings_or_problems(settings, problems)
+    }
+
+    // Reads the config file and applies the environment, checking only that
+    // every value parses. Maintenance commands use this and check just the
+    // sections they need, e.g. with `require_database`.
+    pub fn read() -> Result<Self, SettingsError> {
+        let (path, explicit) = match env::var("CONFIG_FILE") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let mut settings = match read_file(&path) {
+            Ok(Some(settings)) => settings,
+            Ok(None) if !explicit => Settings::default(),
+            Ok(None) => return Err(SettingsError(vec![format!("Config file {} does not exist", path.display())])),
+            Err(e) => return Err(SettingsError(vec![e])),
+        };
+
+        let mut problems = Vec::new();
+        settings.apply_env(&mut problems);
+        settings_or_problems(settings, problems)
+    }
+
+    // Checks the settings needed to connect to the database
+    pub fn require_database(&self) -> Result<(), SettingsError> {
+        let mut problems = Vec::new();
+        self.validate_database(&mut problems);
+        settings_or_problems((), problems)
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        let mut env = EnvOverrides { problems };
+
+        env.set("SERVER_HOST", &mut self.server.host);
+        env.set("SERVER_PORT", &mut self.server.port);
+        env.set_opt("SERVER_WORKERS", &mut self.server.workers);
+        env.set_list("TRUSTED_PROXIES", &mut self.server.trusted_proxies);
+
+        env.set("DATABASE_URL", &mut self.database.url);
+        env.set("DATABASE_MAX_CONNECTIONS", &mut self.database.max_connections);
+        env.set("DATABASE_ACQUIRE_TIMEOUT_SECS", &mut self.database.acquire_timeout_secs);
+        env.set("DATABASE_IDLE_TIMEOUT_SECS", &mut self.database.idle_timeout_secs);
+        env.set("DATABASE_MAX_LIFETIME_SECS", &mut self.database.max_lifetime_secs);
+        env.set("MIGRATE_ON_STARTUP", &mut self.database.migrate_on_startup);
+
+        env.set("SENDGRID_API_KEY", &mut self.email.sendgrid_api_key);
+        env.set("SENDER_EMAIL", &mut self.email.sender_email);
+        env.set("FRONTEND_URL", &mut self.email.frontend_url);
+        env.set("EMAIL_PROVIDER_RULES", &mut self.email.provider_rules);
+
+        env.set("JWT_ALGORITHM", &mut self.jwt.algorithm);
+        env.set_opt("JWT_SECRET", &mut self.jwt.secret);
+        env.set_opt("JWT_SECRET_FILE", &mut self.jwt.secret_file);
+        env.set_opt("JWT_PRIVATE_KEY_FILE", &mut self.jwt.private_key_file);
+        env.set_opt("JWT_KEY_ID", &mut self.jwt.key_id);
+        env.set_list("JWT_VERIFICATION_KEY_FILES", &mut self.jwt.verification_key_files);
+        env.set("JWT_MAX_VERIFICATION_KEYS", &mut self.jwt.max_verification_keys);
+        env.set_opt("JWT_ROTATION_INTERVAL_SECS", &mut self.jwt.rotation_interval_secs);
+
+        env.set("ARGON2_MEMORY_KIB", &mut self.password_hash.memory_kib);
+        env.set("ARGON2_ITERATIONS", &mut self.password_hash.iterations);
+        env.set("ARGON2_PARALLELISM", &mut self.password_hash.parallelism);
+        env.set_opt("HASH_POOL_THREADS", &mut self.password_hash.pool_threads);
+        env.set_opt("HASH_POOL_QUEUE_LIMIT", &mut self.password_hash.queue_limit);
+
+        env.set("PASSWORD_MIN_LENGTH", &mut self.password_policy.min_length);
+        env.set("PASSWORD_MAX_LENGTH", &mut self.password_policy.max_length);
+        env.set("PASSWORD_MIN_CHAR_CLASSES", &mut self.password_policy.min_char_classes);
+        env.set("PASSWORD_MIN_SCORE", &mut self.password_policy.min_score);
+        env.set_opt("PASSWORD_BREACH_DIR", &mut self.password_policy.breach_dir);
+
+        env.set("LOGIN_MAX_ACCOUNT_FAILURES", &mut self.login_throttle.max_account_failures);
+        env.set("LOGIN_MAX_IP_FAILURES", &mut self.login_throttle.max_ip_failures);
+        env.set("LOGIN_LOCKOUT_SECS", &mut self.login_throttle.lockout_secs);
+        env.set("LOGIN_MAX_LOCKOUT_SECS", &mut self.login_throttle.max_lockout_secs);
+        env.set("LOGIN_FAILURE_WINDOW_SECS", &mut self.login_throttle.failure_window_secs);
+
+        env.set("RATE_LIMIT_BACKEND", &mut self.rate_limit.backend);
+
+        env.set_opt("ADMIN_EMAIL", &mut self.admin.email);
+        env.set_opt("ADMIN_PASSWORD", &mut self.admin.password);
+    }
+
+    fn validate_database(&self, problems: &mut Vec<String>) {
+        if self.database.url.trim().is_empty() {
+            problems.push("database.url (DATABASE_URL) must be set".to_string());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database.max_connections must be greater than zero".to_string());
+        }
+    }
+
+    fn validate(&mut self, problems: &mut Vec<String>) {
+        self.validate_database(problems);
+
+        let mut require = |value: &str, name: &str| {
+            if value.trim().is_empty() {
+                problems.push(format!("{} must be set", name));
+            }
+        };
+        require(&self.email.sendgrid_api_key, "email.sendgrid_api_key (SENDGRID_API_KEY)");
+        require(&self.email.sender_email, "email.sender_email (SENDER_EMAIL)");
+        require(&self.email.frontend_url, "email.frontend_url (FRONTEND_URL)");
+
+        if self.server.workers == Some(0) {
+            problems.push("server.workers must be greater than zero".to_string());
+        }
+        let frontend_url = self.email.frontend_url.trim_end_matches('/');
+        if !frontend_url.is_empty() && !frontend_url.starts_with("http://") && !frontend_url.starts_with("https://") {
+            problems.push("email.frontend_url must be an http(s) URL".to_string());
+        }
+        self.email.frontend_url = frontend_url.to_string();
+
+        let jwt = &self.jwt;
+        match jwt.algorithm.as_str() {
+            "HS256" if jwt.secret.is_none() && jwt.secret_file.is_none() => {
+                problems.push("jwt.secret (JWT_SECRET) or jwt.secret_file (JWT_SECRET_FILE) must be set for HS256".to_string())
+            }
+            "RS256" | "ES256" | "EdDSA" if jwt.private_key_file.is_none() => problems.push(format!(
+                "jwt.private_key_file (JWT_PRIVATE_KEY_FILE) must be set for {}",
+                jwt.algorithm
+            )),
+            "HS256" | "RS256" | "ES256" | "EdDSA" => {}
+            other => problems.push(format!("jwt.algorithm {:?} is not one of HS256, RS256, ES256 or EdDSA", other)),
+        }
+        if jwt.algorithm == "HS256" {
+            validate_hs256_secrets(jwt, problems);
+        }
+        if jwt.rotation_interval_secs == Some(0) {
+            problems.push("jwt.rotation_interval_secs must be greater than zero, leave it unset to turn rotation off".to_string());
+        } else if let Some(interval) = jwt.rotation_interval_secs {
+            if !matches!(jwt.algorithm.as_str(), "ES256" | "EdDSA") {
+                problems.push(format!("jwt.rotation_interval_secs is not supported for {} keys", jwt.algorithm));
+            }
+            // A retired key verifies for `max_verification_keys` more
+            // rotations, every token it signed must expire before that
+            let longest = TokenPurpose::longest_lifetime().num_seconds() as u64;
+            if (jwt.max_verification_keys as u64).saturating_mul(interval) < longest {
+                problems.push(format!(
+                    "jwt.max_verification_keys x jwt.rotation_interval_secs must be at least {} seconds, the longest token lifetime",
+                    longest
+                ));
+            }
+        }
+
+        let hash = &self.password_hash;
+        if let Err(e) = hash.params() {
+            problems.push(format!("password_hash parameters are invalid: {}", e));
+        }
+        if hash.pool_threads == Some(0) || hash.queue_limit == Some(0) {
+            problems.push("password_hash.pool_threads and queue_limit must be greater than zero".to_string());
+        }
+
+        let policy = &self.password_policy;
+        if policy.min_length > policy.max_length {
+            problems.push("password_policy.min_length must not exceed max_length".to_string());
+        }
+        if policy.min_char_classes > 4 {
+            problems.push("password_policy.min_char_classes must be at most 4".to_string());
+        }
+        if policy.min_score > 4 {
+            problems.push("password_policy.min_score must be at most 4".to_string());
+        }
+
+        let throttle = &self.login_throttle;
+        if throttle.max_account_failures < 1 || throttle.max_ip_failures < 1 {
+            problems.push("login_throttle.max_*_failures must be at least 1".to_string());
+        }
+        if [throttle.lockout_secs, throttle.max_lockout_secs, throttle.failure_window_secs]
+            .iter()
+            .any(|secs| !secs.is_finite() || *secs < 0.0)
+        {
+            problems.push("login_throttle durations must not be negative".to_string());
+        }
+        if throttle.lockout_secs > throttle.max_lockout_secs {
+            problems.push("login_throttle.lockout_secs must not exceed max_lockout_secs".to_string());
+        }
+    }
+}
+
+fn settings_or_problems<T>(value: T, problems: Vec<String>) -> Result<T, SettingsError> {
+    if problems.is_empty() {
+        Ok(value)
+    } else {
+        Err(SettingsError(problems))
+    }
+}
+
+// Every HS256 secret, signing or verification only, must be long enough to
+// resist brute force. Files are read here so a bad one stops startup with the
+// other problems.
+fn validate_hs256_secrets(jwt: &JwtSettings, problems: &mut Vec<String>) {
+    let check = |name: String, secret: &[u8]| {
+        (secret.trim_ascii().len() < MIN_HS256_SECRET_LEN)
+            .then(|| format!("{} must be at least {} bytes", name, MIN_HS256_SECRET_LEN))
+    };
+
+    let mut files: Vec<&PathBuf> = jwt.verification_key_files.iter().collect();
+    match (&jwt.secret_file, &jwt.secret) {
+        (Some(path), _) => files.insert(0, path),
+        (None, Some(secret)) => problems.extend(check("jwt.secret (JWT_SECRET)".to_string(), secret.as_bytes())),
+        (None, None) => {}
+    }
+    for path in files {
+        match std::fs::read(path) {
+            Ok(secret) => problems.extend(check(format!("HS256 secret in {}", path.display()), &secret)),
+            Err(e) => problems.push(format!("Failed to read HS256 secret {}: {}", path.display(), e)),
+        }
+    }
+}
+
+// `None` if the file does not exist
+fn read_file(path: &Path) -> Result<Option<Settings>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read config file {}: {}", path.display(), e)),
+    };
+    toml::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+// Applies environment variables on top of the file, noting the ones that do
+// not parse
+struct EnvOverrides<'a> {
+    problems: &'a mut Vec<String>,
+}
+
+impl EnvOverrides<'_> {
+    fn set<T: FromStr>(&mut self, name: &str, target: &mut T) {
+        if let Some(value) = self.parse(name) {
+            *target = value;
+        }
+    }
+
+    fn set_opt<T: FromStr>(&mut self, name: &str, target: &mut Option<T>) {
+        if let Some(value) = self.parse(name) {
+            *target = Some(value);
+        }
+    }
+
+    fn set_list<T: FromStr>(&mut self, name: &str, target: &mut Vec<T>) {
+        let Ok(value) = env::var(name) else {
+            return;
+        };
+        let items: Result<Vec<T>, _> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::parse)
+            .collect();
+        match items {
+            Ok(items) => *target = items,
+            Err(_) => self.problems.push(format!("{}: {:?} is not a valid list", name, value)),
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = env::var(name).ok()?;
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.problems.push(format!("{}: {:?} is not a valid value", name, value));
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use actix_web::error::{JsonPayloadError, PathError};
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, ResponseError};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind as JwtErrorKind};
+use serde::Serialize;
+use serde_json::json;
+use tracing::error;
+
+use crate::auth::keys::KeyError;
+use crate::communication::email::EmailError;
+use crate::repositories::user_repository::AuthError;
+
+// Application-wide error type. Every handler returns `Result<_, AppError>` and
+// the response is rendered as `{"code": "...", "error": "..."}` so clients can
+// switch on the stable `code` instead of the human readable message.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    // Rendered with an extra `fields` array describing each problem
+    #[error("Validation failed")]
+    Validation(Vec<FieldError>),
+
+    // The request could not be parsed, e.g. a malformed JSON body or path
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    Unauthorized(String),
+
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("Token has been revoked")]
+    TokenRevoked,
+
+    // Seconds until the lock expires
+    #[error("Account is temporarily locked after too many failed sign-in attempts")]
+    AccountLocked(u64),
+
+    #[error("Too many failed sign-in attempts, please try again later")]
+    TooManyAttempts(u64),
+
+    #[error("Too many requests, please slow down")]
+    RateLimited(u64),
+
+    #[error("Verification link is invalid, expired or has already been used")]
+    InvalidVerificationToken,
+
+    #[error("Password reset link is invalid, expired or has already been used")]
+    InvalidResetToken,
+
+    #[error("Invalid or expired refresh token")]
+    InvalidRefreshToken,
+
+    #[error("Refresh token has already been used")]
+    RefreshTokenReused,
+
+    #[error("{0}")]
+    NotFound(String),
+
+    // A unique key is already taken, e.g. the email of an existing user
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("Database error: {0}")]
+    Database(sqlx::Error),
+
+    #[error("Token error: {0}")]
+    Token(#[from] JwtError),
+
+    #[error("Authentication error: {0}")]
+    Auth(AuthError),
+
+    #[error("Email error: {0}")]
+    Email(#[from] EmailError),
+
+    #[error("Signing key error: {0}")]
+    Keys(KeyError),
+}
+
+// Seconds clients are asked to wait when the server sheds load
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+// A problem with a single field of the request body
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &'static str, message: String) -> Self {
+        Self { field: field.to_string(), code, message }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                AppError::Conflict("A record with these details already exists".to_string())
+            }
+            _ => AppError::Database(e),
+        }
+    }
+}
+
+// Database failures keep their own code instead of `authentication_error`
+impl From<AuthError> for AppError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Database(e) => AppError::from(e),
+            other => AppError::Auth(other),
+        }
+    }
+}
+
+// Database failures keep their own code instead of `key_error`
+impl From<KeyError> for AppError {
+    fn from(e: KeyError) -> Self {
+        match e {
+            KeyError::Database(e) => AppError::from(e),
+            other => AppError::Keys(other),
+        }
+    }
+}
+
+impl AppError {
+    // Stable machine-readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation_failed",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::InvalidCredentials => "invalid_credentials",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::TokenRevoked => "token_revoked",
+            AppError::AccountLocked(_) => "account_locked",
+            AppError::TooManyAttempts(_) => "too_many_attempts",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::InvalidVerificationToken => "invalid_verification_token",
+            AppError::InvalidResetToken => "invalid_reset_token",
+            AppError::InvalidRefreshToken => "invalid_refresh_token",
+            AppError::RefreshTokenReused => "refresh_token_reused",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Database(_) => "database_error",
+            AppError::Token(e) => match e.kind() {
+                JwtErrorKind::ExpiredSignature => "token_expired",
+                kind if is_invalid_token(kind) => "invalid_token",
+                _ => "token_error",
+            },
+            AppError::Auth(AuthError::Busy) => "server_busy",
+            AppError::Auth(_) => "authentication_error",
+            AppError::Email(_) => "email_error",
+            AppError::Keys(_) => "key_error",
+        }
+    }
+
+    // Seconds the client should wait before retrying, sent as `Retry-After`
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            AppError::AccountLocked(secs)
+            | AppError::TooManyAttempts(secs)
+            | AppError::RateLimited(secs) => Some(*secs),
+            AppError::Auth(AuthError::Busy) => Some(BUSY_RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+
+    // Message exposed to the client. Internal failures are not described in
+    // detail, the full error is logged instead.
+    fn public_message(&self) -> String {
+        match self {
+            AppError::Database(_) => "Internal server error".to_string(),
+            AppError::Token(e) => match e.kind() {
+                JwtErrorKind::ExpiredSignature => "Token has expired".to_string(),
+                kind if is_invalid_token(kind) => "Invalid token".to_string(),
+                _ => "Failed to process token".to_string(),
+            },
+            AppError::Auth(AuthError::Busy) => "Server is busy, please try again shortly".to_string(),
+            AppError::Auth(_) => "Authentication failed".to_string(),
+            AppError::Email(_) => "Failed to send email".to_string(),
+            AppError::Keys(_) => "Failed to process signing keys".to_string(),
+            other => other.to_string(),
+        }
+    }
+}
+
+// Token errors caused by the client presenting a bad token, as opposed to
+// failures of our own key material
+fn is_invalid_token(kind: &JwtErrorKind) -> bool {
+    matches!(
+        kind,
+        JwtErrorKind::InvalidToken
+            | JwtErrorKind::InvalidSignature
+            | JwtErrorKind::InvalidAlgorithm
+            | JwtErrorKind::InvalidAlgorithmName
+            | JwtErrorKind::InvalidIssuer
+            | JwtErrorKind::InvalidAudience
+            | JwtErrorKind::InvalidSubject
+            | JwtErrorKind::ImmatureSignature
+            | JwtErrorKind::MissingRequiredClaim(_)
+            | JwtErrorKind::MissingAlgorithm
+            | JwtErrorKind::Base64(_)
+            | JwtErrorKind::Json(_)
+            | JwtErrorKind::Utf8(_)
+    )
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_)
+            | AppError::InvalidCredentials
+            | AppError::TokenRevoked
+            | AppError::InvalidVerificationToken
+            | AppError::InvalidResetToken
+            | AppError::InvalidRefreshToken
+            | AppError::RefreshTokenReused => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::AccountLocked(_)
+            | AppError::TooManyAttempts(_)
+            | AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Token(e) => match e.kind() {
+                JwtErrorKind::ExpiredSignature => StatusCode::UNAUTHORIZED,
+                kind if is_invalid_token(kind) => StatusCode::UNAUTHORIZED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Auth(AuthError::Busy) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_)
+            | AppError::Auth(_)
+            | AppError::Email(_)
+            | AppError::Keys(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // Shedding load is expected under a burst, don't flood the log
+        if status.is_server_error() && !matches!(self, AppError::Auth(AuthError::Busy)) {
+            error!("Request failed: {:?}", self);
+        }
+
+        let mut body = json!({
+            "code": self.code(),
+            "error": self.public_message(),
+        });
+        if let AppError::Validation(fields) = self {
+            body["fields"] = json!(fields);
+        }
+
+        let mut response = HttpResponse::build(status);
+        if let Some(secs) = self.retry_after() {
+            response.insert_header(("Retry-After", secs.to_string()));
+        }
+        response.json(body)
+    }
+}
+
+// Extractor errors are raised by actix before a handler runs. These turn
+// them into `AppError`s so they get the same envelope, registered through
+// `web::JsonConfig` and `web::PathConfig`.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let message = match &err {
+        JsonPayloadError::ContentType => "Content-Type must be application/json".to_string(),
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            "Request body is too large".to_string()
+        }
+        JsonPayloadError::Deserialize(e) => format!("Invalid request body: {}", e),
+        _ => "Invalid request body".to_string(),
+    };
+    AppError::BadRequest(message).into()
+}
+
+pub fn path_error_handler(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    let PathError::Deserialize(e) = err else {
+        return AppError::BadRequest("Invalid path".to_string()).into();
+    };
+    AppError::BadRequest(format!("Invalid path: {}", e)).into()
+}
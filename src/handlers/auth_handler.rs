@@ -1,12 +1,24 @@
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse};
 use serde::Deserialize;
 use serde_json::json;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::auth::jwt::{generate_token, validate_token};
-use crate::communication::email::send_verification_email;
-use crate::models::user::CreateUserRequest;
-use crate::repositories::user_repository::{AuthError, UserRepository};
+use crate::auth::client_ip::ClientIpResolver;
+use crate::auth::email_normalizer::EmailNormalizer;
+use crate::auth::jwt::{generate_session_token, generate_token, issue_token, validate_token, TokenPurpose};
+use crate::auth::keys::JwtKeys;
+use crate::auth::login_throttle::LoginThrottle;
+use crate::auth::middleware::bearer_token;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::principal::AuthenticatedUser;
+use crate::auth::revocation::RevocationStore;
+use crate::communication::email::Mailer;
+use crate::error::AppError;
+use crate::models::user::{User, UserStatus};
+use crate::repositories::email_verification_repository::EmailVerificationRepository;
+use crate::repositories::refresh_token_repository::{RefreshRotation, RefreshTokenRepository};
+use crate::repositories::role_repository::RoleRepository;
+use crate::repositories::user_repository::UserRepository;
 
 #[derive(Deserialize)]
 pub struct SignupRequest {
@@ -24,170 +36,260 @@ pub struct SetpasswordRequest {
     password: String,
 }
 
-// Extract token from Authorization header using a helper function
-fn extract_token_from_header(req: &HttpRequest) -> Result<String, &'static str> {
-    // Get the Authorization header value
-    let auth_header = req.headers()
-        .get("Authorization")
-        .ok_or("Missing Authorization header")?
-        .to_str()
-        .map_err(|_| "Invalid Authorization header")?;
-
-    // Check if it starts with "Bearer " and extract the token
-    if auth_header.starts_with("Bearer ") {
-        Ok(auth_header[7..].to_string())  // Skip "Bearer " prefix
-    } else {
-        Err("Invalid Authorization header format")
-    }
+#[derive(Deserialize)]
+pub struct UnlockRequest {
+    token: String,
 }
 
-pub async fn signup(signup_req: web::Json<SignupRequest>, repo: web::Data<UserRepository>) -> impl Responder {
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    refresh_token: Option<String>,
+}
+
+// Issues a fresh access token plus a refresh token starting a new family
+pub(crate) async fn issue_session(
+    keys: &JwtKeys,
+    roles: &RoleRepository,
+    refresh_tokens: &RefreshTokenRepository,
+    user: User,
+) -> Result<serde_json::Value, AppError> {
+    let grants = roles.grants_for_user(user.uid).await?;
+    let token = generate_session_token(keys, user.uid, user.email, grants)?;
+    let refresh_token = refresh_tokens.issue(user.uid).await?;
+
+    Ok(json!({
+        "token": token,
+        "expires_in": TokenPurpose::Session.lifetime().num_seconds(),
+        "refresh_token": refresh_token
+    }))
+}
+
+// Handler for starting a signup. The response is the same whether or not the
+// email is already registered, and the work happens in the background so the
+// response time does not give it away either.
+pub async fn signup(
+    signup_req: web::Json<SignupRequest>,
+    repo: web::Data<UserRepository>,
+    verifications: web::Data<EmailVerificationRepository>,
+    keys: web::Data<JwtKeys>,
+    normalizer: web::Data<EmailNormalizer>,
+    mailer: web::Data<Mailer>,
+) -> Result<HttpResponse, AppError> {
     info!("Signup request for email: {}", signup_req.email);
 
-    // Check if user already exists
-    match repo.get_user_by_email(&signup_req.email).await {
-        Ok(Some(_)) => {
-            HttpResponse::BadRequest().json(json!({
-                "error": "User with this email already exists"
-            }));
-        }
-        Ok(None) => (),
-        Err(e) => {
-            error!("Database error: {:?}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Internal server error"
-            }));
+    // Only malformed addresses are rejected up front, that reveals nothing
+    let email = normalizer.normalize("email", &signup_req.email)?;
+    actix_web::rt::spawn(async move {
+        if let Err(e) = start_signup(&repo, &verifications, &keys, &mailer, &email).await {
+            error!("Failed to process signup: {:?}", e);
         }
-    }
+    });
 
-    // Generate verification token
-    match generate_token(signup_req.email.clone()) {
-        Ok(token) => {
-            // Send verification email
-            match send_verification_email(&signup_req.email, &token).await {
-                Ok(_) => HttpResponse::Ok().json(json!({
-                    "message": "Verification email sent successfully"
-                })),
-                Err(e) => {
-                    error!("Failed to send verification email: {:?}", e);
-                    HttpResponse::InternalServerError().json(json!({
-                        "error": "Failed to send verification email"
-                    }))
-                }
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Check your inbox to continue signing up"
+    })))
+}
+
+async fn start_signup(
+    repo: &UserRepository,
+    verifications: &EmailVerificationRepository,
+    keys: &JwtKeys,
+    mailer: &Mailer,
+    email: &str,
+) -> Result<(), AppError> {
+    match repo.get_user_by_email(email).await?.map(|user| user.status) {
+        // New email: record the pending signup, then verify it below
+        None => {
+            if repo.create_pending_user(email).await?.is_none() {
+                info!("Concurrent signup for the same email, ignoring");
+                return Ok(());
             }
         }
-        Err(e) => {
-            error!("Token generation failed: {:?}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Failed to generate verification token"
-            }))
+        // Signing up again before verifying resends the verification email
+        Some(UserStatus::PendingVerification) => {
+            info!("Resending verification email for a pending signup");
+        }
+        // Verified users are told they already have an account instead
+        Some(UserStatus::Verified) => {
+            info!("Signup attempted for an existing account");
+            mailer.send_account_exists_email(email).await?;
+            return Ok(());
         }
     }
 
+    // Generate a single-use verification token, record it and send it by email
+    let (token, claims) = issue_token(keys, email.to_string(), TokenPurpose::VerifyEmail)?;
+    verifications.create(claims.jti, email, claims.exp).await?;
+    mailer.send_verification_email(email, &token).await?;
+
+    Ok(())
 }
 
 pub async fn set_password(
-    req: HttpRequest,  // Add HttpRequest parameter to access headers
+    req: HttpRequest,
     password_req: web::Json<SetpasswordRequest>,
     repo: web::Data<UserRepository>,
-) -> impl Responder {
+    roles: web::Data<RoleRepository>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    keys: web::Data<JwtKeys>,
+    policy: web::Data<PasswordPolicy>,
+) -> Result<HttpResponse, AppError> {
     info!("Processing set password request");
 
-    // First, try to extract the token from the Authorization header
-    let token = match extract_token_from_header(&req) {
-        Ok(token) => token,
-        Err(error_msg) => {
-            error!("Authorization header error: {}", error_msg);
-            return HttpResponse::BadRequest().json(json!({
-                "error": error_msg
-            }));
-        }
-    };
+    // The verification token from the email link comes as a bearer token
+    let token = bearer_token(req.headers())?.to_string();
+    let claims = validate_token(&keys, token, TokenPurpose::VerifyEmail)?;
+    policy.validate("password", &password_req.password, &[&claims.sub]).await?;
 
-    // Validate the extracted token
-    match validate_token(token) {
-        Ok(claims) => {
-            // Create new user with email from token claims and password from request
-            let create_user_req = CreateUserRequest {
-                email: claims.sub,  // Email is stored in the 'sub' claim
-                password: password_req.password.clone(),
-            };
-
-            // Attempt to create the user in the database
-            match repo.create_user(create_user_req).await {
-                Ok(created_user) => {
-                    // Generate a new authentication token for the created user
-                    match generate_token(created_user.email) {
-                        Ok(auth_token) => {
-                            info!("User created successfully");
-                            HttpResponse::Ok().json(json!({
-                                "message": "User created successfully",
-                                "token": auth_token,
-                                "expires_in": 3600  // Token expiration in seconds
-                            }))
-                        },
-                        Err(e) => {
-                            error!("Failed to generate authentication token: {:?}", e);
-                            HttpResponse::InternalServerError().json(json!({
-                                "error": "Failed to generate authentication token",
-                                "details": e.to_string()
-                            }))
-                        }
-                    }
-                },
-                Err(e) => {
-                    error!("Failed to create user: {:?}", e);
-                    HttpResponse::InternalServerError().json(json!({
-                        "error": "Failed to create user",
-                        "details": e.to_string()
-                    }))
-                }
-            }
-        },
-        Err(e) => {
-            error!("Token validation failed: {:?}", e);
-            HttpResponse::Unauthorized().json(json!({
-                "error": "Invalid or expired token",
-                "details": e.to_string()
-            }))
-        }
-    }
+    // Mark the verification as used and move the pending user to verified
+    // with the password from the request
+    let created_user = repo.verify_user(claims.jti, &password_req.password)
+        .await?
+        .ok_or(AppError::InvalidVerificationToken)?;
+
+    // Sign the created user in straight away
+    let mut session = issue_session(&keys, &roles, &refresh_tokens, created_user).await?;
+    session["message"] = json!("User created successfully");
+
+    info!("User created successfully");
+    Ok(HttpResponse::Ok().json(session))
 }
 
 // Handler for signin
+#[allow(clippy::too_many_arguments)] // one per extractor
 pub async fn signin(
+    req: HttpRequest,
     signin_req: web::Json<SigninRequest>,
     repo: web::Data<UserRepository>,
-) -> impl Responder {
+    roles: web::Data<RoleRepository>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    keys: web::Data<JwtKeys>,
+    throttle: web::Data<LoginThrottle>,
+    normalizer: web::Data<EmailNormalizer>,
+    mailer: web::Data<Mailer>,
+    client_ip: web::Data<ClientIpResolver>,
+) -> Result<HttpResponse, AppError> {
     info!("Signin request for email: {}", signin_req.email);
 
-    match repo.authenticate_user(&signin_req.email, &signin_req.password).await {
-        Ok(true) => {
-            match generate_token(signin_req.email.clone()) {
-                Ok(token) => HttpResponse::Ok().json(json!({
-                    "token": token,
-                    "expires_in": 3600
-                })),
-                Err(e) => {
-                    error!("Token generation failed: {:?}", e);
-                    HttpResponse::InternalServerError().json(json!({
-                        "error": "Failed to generate token"
-                    }))
+    let email = normalizer.normalize("email", &signin_req.email)?;
+    let ip = client_ip.client_ip(&req);
+    throttle.check(&email, ip).await?;
+
+    let Some(user) = repo.authenticate_user(&email, &signin_req.password).await? else {
+        let outcome = throttle.record_failure(&email, ip).await?;
+        if outcome.account_locked {
+            warn!("Account locked after failed sign-ins: {}", email);
+            actix_web::rt::spawn(async move {
+                if let Err(e) = send_unlock_link(&repo, &keys, &mailer, &email).await {
+                    error!("Failed to send account unlock email: {:?}", e);
                 }
-            }
+            });
         }
-        Ok(false) => HttpResponse::Unauthorized().json(json!({
-            "error": "Invalid credentials"
-        })),
-        Err(e) => {
-            error!("Authentication error: {:?}", e);
-            // match e {
-            //     AuthError::UserNotFound => HttpResponse::NotFound(),
-            //     _ => HttpResponse::InternalServerError(),
-            // }
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Authentication failed"
-            }))
+        return Err(AppError::InvalidCredentials);
+    };
+
+    throttle.record_success(&user.email).await?;
+    Ok(HttpResponse::Ok().json(issue_session(&keys, &roles, &refresh_tokens, user).await?))
+}
+
+// Emails the owner of a locked account a link to unlock it. Unknown emails
+// are locked all the same, but nobody is told.
+pub(crate) async fn send_unlock_link(
+    repo: &UserRepository,
+    keys: &JwtKeys,
+    mailer: &Mailer,
+    email: &str,
+) -> Result<(), AppError> {
+    if repo.get_user_by_email(email).await?.is_none() {
+        return Ok(());
+    }
+
+    let token = generate_token(keys, email.to_string(), TokenPurpose::UnlockAccount)?;
+    mailer.send_account_unlock_email(email, &token).await?;
+    Ok(())
+}
+
+// Handler for the unlock link emailed when an account gets locked
+pub async fn unlock_account(
+    unlock_req: web::Json<UnlockRequest>,
+    keys: web::Data<JwtKeys>,
+    throttle: web::Data<LoginThrottle>,
+) -> Result<HttpResponse, AppError> {
+    let claims = validate_token(&keys, unlock_req.into_inner().token, TokenPurpose::UnlockAccount)?;
+    throttle.unlock(&claims.sub).await?;
+
+    info!("Account unlocked: {}", claims.sub);
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Account unlocked"
+    })))
+}
+
+// Handler for exchanging a refresh token for a new access and refresh token
+pub async fn refresh_token(
+    refresh_req: web::Json<RefreshRequest>,
+    repo: web::Data<UserRepository>,
+    roles: web::Data<RoleRepository>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    keys: web::Data<JwtKeys>,
+) -> Result<HttpResponse, AppError> {
+    let (user_uid, refresh_token) = match refresh_tokens.rotate(&refresh_req.refresh_token).await? {
+        RefreshRotation::Rotated { user_uid, refresh_token } => (user_uid, refresh_token),
+        RefreshRotation::Reused => {
+            warn!("Refresh token reuse detected, token family revoked");
+            return Err(AppError::RefreshTokenReused);
         }
+        RefreshRotation::Invalid => return Err(AppError::InvalidRefreshToken),
+    };
+
+    let user = repo.get_user_by_id(user_uid)
+        .await?
+        .ok_or(AppError::InvalidRefreshToken)?;
+    let grants = roles.grants_for_user(user.uid).await?;
+    let token = generate_session_token(&keys, user.uid, user.email, grants)?;
+
+    info!("Refreshed session for user: {}", user_uid);
+    Ok(HttpResponse::Ok().json(json!({
+        "token": token,
+        "expires_in": TokenPurpose::Session.lifetime().num_seconds(),
+        "refresh_token": refresh_token
+    })))
+}
+
+// Handler for logging out the current session
+pub async fn logout(
+    user: AuthenticatedUser,
+    logout_req: Option<web::Json<LogoutRequest>>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    revocations: web::Data<RevocationStore>,
+) -> Result<HttpResponse, AppError> {
+    revocations.revoke_token(user.token_id, user.token_expires_at).await?;
+    if let Some(refresh_token) = logout_req.and_then(|body| body.into_inner().refresh_token) {
+        refresh_tokens.revoke_family(&refresh_token).await?;
     }
-}
\ No newline at end of file
+
+    info!("User logged out: {}", user.uid);
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Logged out successfully"
+    })))
+}
+
+// Handler for logging out every session of the user
+pub async fn logout_all(
+    user: AuthenticatedUser,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    revocations: web::Data<RevocationStore>,
+) -> Result<HttpResponse, AppError> {
+    revocations.revoke_subject(&user.email).await?;
+    revocations.revoke_token(user.token_id, user.token_expires_at).await?;
+    refresh_tokens.revoke_all_for_user(user.uid).await?;
+
+    info!("All sessions logged out for user: {}", user.uid);
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Logged out of all sessions"
+    })))
+}
@@ -0,0 +1,104 @@
+use actix_web::{http::header, web, HttpResponse};
+use actix_web::rt::time::timeout;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+use crate::communication::email::Mailer;
+use crate::config::database::{read_migration_status, MigrationState};
+
+// How long each database check may take before the service counts as not ready
+const DATABASE_DEADLINE: Duration = Duration::from_secs(2);
+
+// Liveness: the process is up and serving requests. Looks at nothing else, so
+// an outage of a dependency does not get the process restarted.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(json!({ "status": "ok" }))
+}
+
+// Readiness: every dependency needed to serve requests works. Responds with
+// 503 and the failing checks otherwise. Failure details are logged rather
+// than returned, the endpoint is public.
+pub async fn readyz(pool: web::Data<PgPool>, mailer: web::Data<Mailer>) -> HttpResponse {
+    let (database, migrations) = futures::join!(check_database(&pool), check_migrations(&pool));
+    let email = check_email(&mailer);
+
+    let ready = [&database, &migrations, &email].iter().all(|check| check["status"] == "ok");
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": {
+            "database": database,
+            "migrations": migrations,
+            "email": email,
+        }
+    });
+
+    let mut response = if ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    response.insert_header((header::CACHE_CONTROL, "no-store")).json(body)
+}
+
+// A connection can be acquired and answers a query within the deadline
+async fn check_database(pool: &PgPool) -> Value {
+    let started = Instant::now();
+    let result = timeout(DATABASE_DEADLINE, async {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await
+    })
+    .await;
+
+    match result {
+        Ok(Ok(_)) => json!({ "status": "ok", "latency_ms": started.elapsed().as_millis() }),
+        Ok(Err(e)) => {
+            error!("Readiness check: database query failed: {}", e);
+            failed("query failed")
+        }
+        Err(_) => {
+            warn!("Readiness check: database did not answer within {:?}", DATABASE_DEADLINE);
+            failed("timed out")
+        }
+    }
+}
+
+// Every embedded migration is applied and unchanged. Only reads, a database
+// that was never migrated is not ready.
+async fn check_migrations(pool: &PgPool) -> Value {
+    let statuses = match timeout(DATABASE_DEADLINE, read_migration_status(pool)).await {
+        Ok(Ok(Some(statuses))) => statuses,
+        Ok(Ok(None)) => {
+            warn!("Readiness check: the migrations table does not exist");
+            return failed("not migrated");
+        }
+        Ok(Err(e)) => {
+            error!("Readiness check: reading migrations failed: {}", e);
+            return failed("status unavailable");
+        }
+        Err(_) => {
+            warn!("Readiness check: migrations did not answer within {:?}", DATABASE_DEADLINE);
+            return failed("timed out");
+        }
+    };
+
+    let versions = |state: MigrationState| -> Vec<i64> {
+        statuses.iter().filter(|status| status.state == state).map(|status| status.version).collect()
+    };
+    let (pending, modified) = (versions(MigrationState::Pending), versions(MigrationState::Modified));
+    let status = if pending.is_empty() && modified.is_empty() { "ok" } else { "failed" };
+    json!({ "status": status, "pending": pending, "modified": modified })
+}
+
+// The email transport is configured. Only checks the settings, SendGrid is
+// not contacted.
+fn check_email(mailer: &Mailer) -> Value {
+    if mailer.is_configured() {
+        json!({ "status": "ok" })
+    } else {
+        failed("not configured")
+    }
+}
+
+fn failed(reason: &str) -> Value {
+    json!({ "status": "failed", "reason": reason })
+}
@@ -0,0 +1,35 @@
+use actix_web::{http::header, web, HttpResponse};
+use serde_json::json;
+use tracing::info;
+
+use crate::auth::keys::{JwtKeys, ACTIVATION_DELAY};
+use crate::auth::principal::AuthenticatedUser;
+use crate::error::AppError;
+
+// Publishes the public signing keys so other services can verify our tokens
+pub async fn jwks(keys: web::Data<JwtKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(keys.jwks())
+}
+
+// Rotates the signing key on every instance. The new key is published right
+// away and starts signing after the activation delay.
+pub async fn rotate_keys(keys: web::Data<JwtKeys>, principal: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    if !principal.has_permission("keys:rotate") {
+        info!("User {} may not rotate signing keys", principal.uid);
+        return Err(AppError::Forbidden("You are not allowed to rotate signing keys".to_string()));
+    }
+    if !keys.can_rotate() {
+        return Err(AppError::Conflict(
+            "The configured signing algorithm does not support rotation".to_string(),
+        ));
+    }
+
+    let kid = keys.rotate().await?;
+    info!("User {} rotated the signing key to {}", principal.uid, kid);
+    Ok(HttpResponse::Ok().json(json!({
+        "kid": kid,
+        "active_in_secs": ACTIVATION_DELAY.as_secs(),
+    })))
+}
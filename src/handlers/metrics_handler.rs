@@ -0,0 +1,10 @@
+use actix_web::{web, HttpResponse};
+
+use crate::auth::hash_pool::HashPool;
+
+// Prometheus metrics for scraping by monitoring
+pub async fn metrics(hash_pool: web::Data<HashPool>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(hash_pool.render_metrics())
+}
@@ -1,2 +1,6 @@
 pub mod user_handler;
-pub mod auth_handler;
\ No newline at end of file
+pub mod auth_handler;
+pub mod jwks_handler;
+pub mod password_handler;
+pub mod metrics_handler;
+pub mod health_handler;
\ No newline at end of file
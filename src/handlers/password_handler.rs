@@ -0,0 +1,167 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::auth::client_ip::ClientIpResolver;
+use crate::auth::email_normalizer::EmailNormalizer;
+use crate::auth::jwt::{issue_token, validate_token, TokenPurpose};
+use crate::auth::keys::JwtKeys;
+use crate::auth::login_throttle::LoginThrottle;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::principal::AuthenticatedUser;
+use crate::auth::revocation::RevocationStore;
+use crate::communication::email::Mailer;
+use crate::error::AppError;
+use crate::handlers::auth_handler::{issue_session, send_unlock_link};
+use crate::models::user::UserStatus;
+use crate::repositories::password_reset_repository::PasswordResetRepository;
+use crate::repositories::refresh_token_repository::RefreshTokenRepository;
+use crate::repositories::role_repository::RoleRepository;
+use crate::repositories::user_repository::UserRepository;
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+// Handler for requesting a password reset email. The response is the same
+// whether or not the account exists, and the work happens in the background
+// so the response time does not give it away either.
+pub async fn forgot_password(
+    forgot_req: web::Json<ForgotPasswordRequest>,
+    repo: web::Data<UserRepository>,
+    resets: web::Data<PasswordResetRepository>,
+    keys: web::Data<JwtKeys>,
+    normalizer: web::Data<EmailNormalizer>,
+    mailer: web::Data<Mailer>,
+) -> Result<HttpResponse, AppError> {
+    info!("Password reset requested for email: {}", forgot_req.email);
+
+    let email = normalizer.normalize("email", &forgot_req.email)?;
+    actix_web::rt::spawn(async move {
+        if let Err(e) = send_reset_link(&repo, &resets, &keys, &mailer, &email).await {
+            error!("Failed to send password reset email: {:?}", e);
+        }
+    });
+
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "If an account exists for this email, a password reset link has been sent"
+    })))
+}
+
+async fn send_reset_link(
+    repo: &UserRepository,
+    resets: &PasswordResetRepository,
+    keys: &JwtKeys,
+    mailer: &Mailer,
+    email: &str,
+) -> Result<(), AppError> {
+    let Some(user) = repo.get_user_by_email(email).await? else {
+        info!("Password reset requested for unknown email");
+        return Ok(());
+    };
+    // Pending users set their password through the verification email
+    if user.status != UserStatus::Verified {
+        info!("Password reset requested for an unverified account");
+        return Ok(());
+    }
+
+    // Generate a single-use reset token, record it and send it by email
+    let (token, claims) = issue_token(keys, user.email.clone(), TokenPurpose::PasswordReset)?;
+    resets.create(claims.jti, user.uid, claims.exp).await?;
+    mailer.send_password_reset_email(&user.email, &token).await?;
+
+    Ok(())
+}
+
+// Handler for choosing a new password with a reset token
+pub async fn reset_password(
+    reset_req: web::Json<ResetPasswordRequest>,
+    repo: web::Data<UserRepository>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    revocations: web::Data<RevocationStore>,
+    keys: web::Data<JwtKeys>,
+    policy: web::Data<PasswordPolicy>,
+) -> Result<HttpResponse, AppError> {
+    let reset_req = reset_req.into_inner();
+    let claims = validate_token(&keys, reset_req.token, TokenPurpose::PasswordReset)?;
+    policy.validate("password", &reset_req.password, &[&claims.sub]).await?;
+
+    // Mark the reset as used and store the new password hash
+    let user = repo.reset_password(claims.jti, &reset_req.password)
+        .await?
+        .ok_or(AppError::InvalidResetToken)?;
+
+    // Sign the user out everywhere, the old password may have been compromised
+    revocations.revoke_subject(&user.email).await?;
+    refresh_tokens.revoke_all_for_user(user.uid).await?;
+
+    info!("Password reset for user: {}", user.uid);
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Password has been reset"
+    })))
+}
+
+// Handler for a signed in user changing their own password
+#[allow(clippy::too_many_arguments)] // one per extractor
+pub async fn change_password(
+    req: HttpRequest,
+    principal: AuthenticatedUser,
+    change_req: web::Json<ChangePasswordRequest>,
+    repo: web::Data<UserRepository>,
+    roles: web::Data<RoleRepository>,
+    refresh_tokens: web::Data<RefreshTokenRepository>,
+    revocations: web::Data<RevocationStore>,
+    keys: web::Data<JwtKeys>,
+    policy: web::Data<PasswordPolicy>,
+    throttle: web::Data<LoginThrottle>,
+    mailer: web::Data<Mailer>,
+    client_ip: web::Data<ClientIpResolver>,
+) -> Result<HttpResponse, AppError> {
+    // A stolen access token alone must not be enough to take over the account,
+    // so guesses at the current password count towards the sign-in lockout
+    let ip = client_ip.client_ip(&req);
+    throttle.check(&principal.email, ip).await?;
+    let Some(user) = repo.authenticate_user(&principal.email, &change_req.current_password).await? else {
+        let outcome = throttle.record_failure(&principal.email, ip).await?;
+        if outcome.account_locked {
+            warn!("Account locked after failed password changes: {}", principal.email);
+            let email = principal.email.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = send_unlock_link(&repo, &keys, &mailer, &email).await {
+                    error!("Failed to send account unlock email: {:?}", e);
+                }
+            });
+        }
+        return Err(AppError::InvalidCredentials);
+    };
+    throttle.record_success(&user.email).await?;
+    policy.validate("new_password", &change_req.new_password, &[&principal.email]).await?;
+
+    repo.update_password(user.uid, &change_req.new_password).await?;
+
+    // Sign out every other session and hand the caller a fresh one, tokens
+    // issued from this second on are not affected by the subject revocation
+    revocations.revoke_subject(&user.email).await?;
+    revocations.revoke_token(principal.token_id, principal.token_expires_at).await?;
+    refresh_tokens.revoke_all_for_user(user.uid).await?;
+
+    info!("Password changed for user: {}", user.uid);
+    let mut session = issue_session(&keys, &roles, &refresh_tokens, user).await?;
+    session["message"] = json!("Password changed successfully");
+
+    Ok(HttpResponse::Ok().json(session))
+}
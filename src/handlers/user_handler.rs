@@ -1,56 +1,60 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse};
 use uuid::Uuid;
-use crate::models::user::CreateUserRequest;
+use crate::auth::email_normalizer::EmailNormalizer;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::principal::AuthenticatedUser;
+use crate::error::AppError;
+use crate::models::user::{CreateUserRequest, PublicUser};
 use crate::repositories::user_repository::UserRepository;
-use tracing::{error, info, instrument};
-use serde_json::json;  // Add this import for json! macro
+use tracing::{info, instrument};
 
-#[instrument(skip(repo, user))]
+#[instrument(skip(repo, user, policy, normalizer, principal))]
 pub async fn create_user(
     repo: web::Data<UserRepository>,
     user: web::Json<CreateUserRequest>,
-) -> impl Responder {
+    policy: web::Data<PasswordPolicy>,
+    normalizer: web::Data<EmailNormalizer>,
+    principal: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     info!("Attempting to create user with email: {}", user.email);
 
-    match repo.create_user(user.into_inner()).await {
-        Ok(created_user) => {
-            info!("Successfully created user with id: {}", created_user.uid);
-            HttpResponse::Created().json(created_user)
-        },
-        Err(err) => {
-            error!("Failed to create user: {:?}", err);
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Failed to create user",
-                "details": err.to_string()
-            }))
-        }
+    // Creates accounts without email verification, so only for callers
+    // granted `users:create` (admins by default)
+    if !principal.has_permission("users:create") {
+        info!("User {} may not create users", principal.uid);
+        return Err(AppError::Forbidden("You are not allowed to create users".to_string()));
     }
+
+    let mut user = user.into_inner();
+    user.email = normalizer.normalize("email", &user.email)?;
+    policy.validate("password", &user.password, &[&user.email]).await?;
+
+    let created_user = repo.create_user(user).await?;
+
+    info!("Successfully created user with id: {}", created_user.uid);
+    Ok(HttpResponse::Created().json(PublicUser::from(created_user)))
 }
 
-#[instrument(skip(repo))]
+#[instrument(skip(repo, principal))]
 pub async fn get_user(
     repo: web::Data<UserRepository>,
     id: web::Path<Uuid>,
-) -> impl Responder {
+    principal: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     info!("Attempting to fetch user with id: {}", id);
 
-    match repo.get_user_by_id(id.into_inner()).await {
-        Ok(Some(user)) => {
-            info!("Successfully retrieved user");
-            HttpResponse::Ok().json(user)
-        },
-        Ok(None) => {
-            info!("User not found");
-            HttpResponse::NotFound().json(json!({
-                "error": "User not found"
-            }))
-        },
-        Err(err) => {
-            error!("Failed to fetch user: {:?}", err);
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Failed to fetch user",
-                "details": err.to_string()
-            }))
-        }
+    // Users may only read themselves unless granted `users:read`. Anyone
+    // else gets the same 404 as for a missing user, before the database is
+    // asked, so probing ids reveals nothing.
+    if *id != principal.uid && !principal.has_permission("users:read") {
+        info!("User {} may not read user {}", principal.uid, id);
+        return Err(AppError::NotFound("User not found".to_string()));
     }
-}
\ No newline at end of file
+
+    let user = repo.get_user_by_id(id.into_inner())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    info!("Successfully retrieved user");
+    Ok(HttpResponse::Ok().json(PublicUser::from(user)))
+}
@@ -3,19 +3,51 @@ mod handlers;
 mod models;
 mod repositories;
 mod auth;
+mod commands;
 mod communication;
+mod error;
+mod rate_limit;
 
 use actix_web::{web, App, HttpServer, middleware::Logger};
+use auth::access::default_access_rules;
+use auth::bootstrap::bootstrap_admin;
+use auth::client_ip::ClientIpResolver;
+use auth::email_normalizer::EmailNormalizer;
+use auth::keys::{JwtKeys, KEY_SYNC_INTERVAL};
+use auth::login_throttle::LoginThrottle;
+use auth::password_hash::Argon2Hasher;
+use auth::password_policy::PasswordPolicy;
 use auth::middleware::AuthMiddleware;
+use auth::revocation::RevocationStore;
+use communication::email::Mailer;
+use config::settings::{Settings, SettingsError};
+use error::{json_error_handler, path_error_handler};
+use rate_limit::middleware::RateLimitMiddleware;
 use handlers::{
     user_handler::{create_user, get_user},
-    auth_handler::{signin, signup, set_password}
+    auth_handler::{logout, logout_all, refresh_token, signin, signup, set_password, unlock_account},
+    jwks_handler::{jwks, rotate_keys},
+    metrics_handler::metrics,
+    health_handler::{healthz, readyz},
+    password_handler::{change_password, forgot_password, reset_password},
 };
+use repositories::email_verification_repository::EmailVerificationRepository;
+use repositories::password_reset_repository::PasswordResetRepository;
+use repositories::refresh_token_repository::RefreshTokenRepository;
+use repositories::role_repository::RoleRepository;
 use repositories::user_repository::UserRepository;
-use tracing::{info, Level};
+use tracing::{debug, error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 use dotenv::dotenv;
 use std::env;
+use std::time::Duration;
+
+// How often the token revocation cache is re-synced from the database
+const REVOCATION_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+// How often expired sign-in failure counters are deleted
+const LOGIN_FAILURE_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+// How often full rate limit buckets are forgotten
+const RATE_LIMIT_PURGE_INTERVAL: Duration = Duration::from_secs(300);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -26,14 +58,17 @@ async fn main() -> std::io::Result<()> {
         // Continue execution as environment variables might be set through other means
     }
 
-    // Verify that required environment variables are present
-    let required_vars = ["SENDGRID_API_KEY", "FRONTEND_URL", "SENDER_EMAIL"];
-    for var in required_vars.iter() {
-        if env::var(var).is_err() {
-            eprintln!("Required environment variable '{}' is not set", var);
-        }
+    // Maintenance commands run instead of the server, and only check the
+    // settings they use
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        let settings = Settings::read().unwrap_or_else(|e| exit_with(e));
+        std::process::exit(commands::run(command, &args[1..], &settings).await);
     }
 
+    // Load and check every setting up front, refusing to start on any problem
+    let settings = Settings::load().unwrap_or_else(|e| exit_with(e));
+
     // Initialize better logging
     FmtSubscriber::builder()
         .with_max_level(Level::DEBUG)
@@ -46,29 +81,177 @@ async fn main() -> std::io::Result<()> {
     info!("Starting server...");
 
     // Create database pool
-    let pool = config::database::create_pool()
+    let pool = config::database::create_pool(&settings.database)
         .await
         .expect("Failed to create pool");
 
     info!("Database pool created successfully");
 
-    // Create user repository
-    let user_repository = web::Data::new(UserRepository::new(pool));
+    // Bring the schema up to date, unless deployments run `migrate up` themselves
+    if settings.database.migrate_on_startup {
+        config::database::MIGRATOR.run(&pool).await.expect("Failed to run database migrations");
+        info!("Database migrations applied");
+    }
+
+    // Create repositories
+    let hasher = Argon2Hasher::new(&settings.password_hash).expect("Failed to load Argon2 parameters");
+    let hash_pool = web::Data::from(hasher.pool());
+    let user_repository = web::Data::new(UserRepository::new(pool.clone(), hasher));
+    let refresh_token_repository = web::Data::new(RefreshTokenRepository::new(pool.clone()));
+    let email_verification_repository = web::Data::new(EmailVerificationRepository::new(pool.clone()));
+    let password_reset_repository = web::Data::new(PasswordResetRepository::new(pool.clone()));
+    let role_repository = web::Data::new(RoleRepository::new(pool.clone()));
+    // For the readiness check
+    let db_pool = web::Data::new(pool.clone());
+
+    // Sign-in throttling, with stale counters cleaned up in the background
+    let login_throttle = web::Data::new(LoginThrottle::new(pool.clone(), &settings.login_throttle));
+    {
+        let throttle = login_throttle.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(LOGIN_FAILURE_PURGE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                match throttle.purge().await {
+                    Ok(purged) => debug!("Purged {} stale login failure counter(s)", purged),
+                    Err(e) => error!("Failed to purge login failures: {}", e),
+                }
+            }
+        });
+    }
+
+    // Rate limiting, with full buckets cleaned up in the background
+    let rate_limit_backend = rate_limit::backend_from_settings(pool.clone(), &settings.rate_limit);
+    {
+        let backend = rate_limit_backend.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(RATE_LIMIT_PURGE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                match backend.purge().await {
+                    Ok(purged) => debug!("Purged {} full rate limit bucket(s)", purged),
+                    Err(e) => error!("Failed to purge rate limit buckets: {}", e),
+                }
+            }
+        });
+    }
+    // Clients behind the configured reverse proxies are told apart by the
+    // forwarding headers, for rate limiting and sign-in throttling
+    let client_ip = ClientIpResolver::new(settings.server.trusted_proxies.clone());
+    let rate_limit = RateLimitMiddleware::new(rate_limit_backend, rate_limit::default_rules(), client_ip.clone());
+    let client_ip = web::Data::new(client_ip);
+
+    // Load revoked tokens and keep the cache in sync with other instances
+    let revocation_store = web::Data::new(
+        RevocationStore::load(pool.clone()).await.expect("Failed to load token revocations"),
+    );
+    {
+        let store = revocation_store.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(REVOCATION_SYNC_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                if let Err(e) = store.sync().await {
+                    error!("Failed to sync token revocations: {}", e);
+                }
+            }
+        });
+    }
+
+    // Load JWT signing keys, including the ones rotated by any instance
+    let jwt_keys = web::Data::new(
+        JwtKeys::load(&settings.jwt, pool).await.expect("Failed to load JWT keys"),
+    );
+    if jwt_keys.can_rotate() {
+        let keys = jwt_keys.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(KEY_SYNC_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                // Rotates on the configured schedule, whichever instance gets there first
+                if let Err(e) = keys.rotate_if_due().await {
+                    error!("JWT key rotation failed: {}", e);
+                }
+                if let Err(e) = keys.sync().await {
+                    error!("Failed to sync JWT signing keys: {}", e);
+                }
+            }
+        });
+    }
+
+    // Load the password policy
+    let password_policy = web::Data::new(PasswordPolicy::new(&settings.password_policy));
+
+    // Load the email address normalization rules
+    let email_normalizer = web::Data::new(EmailNormalizer::new(settings.email.provider_rules));
+
+    // Email delivery, sharing one HTTP client between sends
+    let mailer = web::Data::new(Mailer::new(&settings.email));
+
+    // Make sure someone can manage users on a fresh deployment
+    bootstrap_admin(&user_repository, &role_repository, &password_policy, &email_normalizer, &settings.admin)
+        .await
+        .expect("Failed to bootstrap the admin account");
+
+    let auth = AuthMiddleware::new(default_access_rules());
+
+    // Services took the sections they need above, handlers don't read settings
+    let bind_address = (settings.server.host.clone(), settings.server.port);
+    let workers = settings.server.workers;
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default()) // Add logging middleware
-            .wrap(AuthMiddleware) // Let's add auth middleware
+            .wrap(rate_limit.clone()) // Runs inside auth so it can key by user
+            .wrap(auth.clone()) // Enforces the access rules
             .wrap(Logger::new("%a %r %s %b %{Referer}i %{User-Agent}i %T")) // Detailed logging
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(web::PathConfig::default().error_handler(path_error_handler))
             .app_data(user_repository.clone())
+            .app_data(refresh_token_repository.clone())
+            .app_data(email_verification_repository.clone())
+            .app_data(password_reset_repository.clone())
+            .app_data(role_repository.clone())
+            .app_data(revocation_store.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(password_policy.clone())
+            .app_data(email_normalizer.clone())
+            .app_data(mailer.clone())
+            .app_data(db_pool.clone())
+            .app_data(client_ip.clone())
+            .app_data(hash_pool.clone())
+            .app_data(login_throttle.clone())
             .route("/signup", web::post().to(signup))
             .route("/signin", web::post().to(signin))
+            .route("/token/refresh", web::post().to(refresh_token))
+            .route("/logout", web::post().to(logout))
+            .route("/logout/all", web::post().to(logout_all))
+            .route("/password/forgot", web::post().to(forgot_password))
+            .route("/password/reset", web::post().to(reset_password))
+            .route("/me/password", web::post().to(change_password))
+            .route("/account/unlock", web::post().to(unlock_account))
             .route("/setpassword", web::post().to(set_password))
             .route("/users", web::post().to(create_user))
             .route("/users/{id}", web::get().to(get_user))
-    }).workers(28)
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await
-}
\ No newline at end of file
+            .route("/.well-known/jwks.json", web::get().to(jwks))
+            .route("/admin/keys/rotate", web::post().to(rotate_keys))
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+    });
+
+    // Defaults to one worker per CPU
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    server.bind(bind_address)?.run().await
+}
+
+fn exit_with(e: SettingsError) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
+}
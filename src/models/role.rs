@@ -0,0 +1,6 @@
+// Roles a user holds and the permissions they add up to
+#[derive(Debug, Clone, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
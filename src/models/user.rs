@@ -1,15 +1,52 @@
 use serde:: {Deserialize, Serialize};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+// Where a user is in the signup flow. A user that does not exist yet is "new".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    // Signed up, waiting for the email to be verified and a password set
+    PendingVerification,
+    Verified,
+}
+
+// A row of the users table. Deliberately not `Serialize`: it carries the
+// password hash, responses use `PublicUser` instead.
+#[derive(Debug)]
 pub struct User {
     pub uid: Uuid,
     pub email: String,
-    pub password: String,
+    // Only pending users have no password
+    pub password: Option<String>,
+    pub status: UserStatus,
+    pub created_at: OffsetDateTime,
+}
+
+// What clients get to see of a user
+#[derive(Debug, Serialize)]
+pub struct PublicUser {
+    pub uid: Uuid,
+    pub email: String,
+    pub status: UserStatus,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        Self {
+            uid: user.uid,
+            email: user.email,
+            status: user.status,
+            created_at: user.created_at,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateUserRequest {
     pub email: String,
     pub password: String,
-}
\ No newline at end of file
+}
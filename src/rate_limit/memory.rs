@@ -0,0 +1,49 @@
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{take_token, Decision, Quota, RateLimitBackend, RateLimitError};
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // Once full the bucket is indistinguishable from a fresh one
+    full_at: Instant,
+}
+
+// Buckets kept in this process only. Each instance enforces the quotas on
+// its own, so with several instances the effective limit is multiplied.
+#[derive(Default)]
+pub struct MemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimitBackend for MemoryBackend {
+    fn hit<'a>(&'a self, key: &'a str, quota: Quota) -> BoxFuture<'a, Result<Decision, RateLimitError>> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let (tokens, elapsed) = match buckets.get(key) {
+            Some(bucket) => (bucket.tokens, now.duration_since(bucket.updated_at).as_secs_f64()),
+            None => (quota.burst as f64, 0.0),
+        };
+        let (left, decision) = take_token(tokens, elapsed, quota);
+
+        buckets.insert(key.to_string(), Bucket {
+            tokens: left,
+            updated_at: now,
+            full_at: now + Duration::from_secs(decision.reset_secs),
+        });
+        Box::pin(async move { Ok(decision) })
+    }
+
+    fn purge(&self) -> BoxFuture<'_, Result<u64, RateLimitError>> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.full_at > now);
+        let purged = (before - buckets.len()) as u64;
+        Box::pin(async move { Ok(purged) })
+    }
+}
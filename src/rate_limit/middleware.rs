@@ -0,0 +1,155 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    body::EitherBody,
+    Error, HttpMessage, ResponseError,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+use crate::auth::client_ip::ClientIpResolver;
+use crate::auth::principal::AuthenticatedUser;
+use crate::error::AppError;
+use super::{Decision, KeyBy, Quota, RateLimitBackend, RateLimitRule};
+
+// Counts every request against the quotas of the rules it matches and
+// rejects it with 429 once one of them is exhausted. Must be registered
+// before `AuthMiddleware` so it runs inside it and can key by subject.
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    limiter: Arc<RateLimiter>,
+}
+
+struct RateLimiter {
+    backend: Arc<dyn RateLimitBackend>,
+    rules: Vec<RateLimitRule>,
+    client_ip: ClientIpResolver,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(backend: Arc<dyn RateLimitBackend>, rules: Vec<RateLimitRule>, client_ip: ClientIpResolver) -> Self {
+        Self { limiter: Arc::new(RateLimiter { backend, rules, client_ip }) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddlewareService {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddlewareService<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let keys = limiter.keys(&req);
+
+            // Report the tightest quota; stop at the first exhausted one
+            let mut tightest: Option<Decision> = None;
+            for (key, quota) in keys {
+                let decision = match limiter.backend.hit(&key, quota).await {
+                    Ok(decision) => decision,
+                    Err(e) => {
+                        // Don't take the whole API down with the backend
+                        error!("Rate limit backend failed, allowing request: {}", e);
+                        continue;
+                    }
+                };
+
+                if !decision.allowed {
+                    warn!("Rate limit exceeded for {}", key);
+                    let err = AppError::RateLimited(decision.retry_after_secs.max(1));
+                    let (request, _) = req.into_parts();
+                    let mut res = ServiceResponse::new(request, err.error_response());
+                    set_headers(res.headers_mut(), &decision);
+                    return Ok(res.map_into_right_body());
+                }
+                if tightest.is_none_or(|t| decision.remaining < t.remaining) {
+                    tightest = Some(decision);
+                }
+            }
+
+            let mut res = service.call(req).await?;
+            if let Some(decision) = tightest {
+                set_headers(res.headers_mut(), &decision);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+impl RateLimiter {
+    // Bucket keys and quotas of every rule the request matches
+    fn keys(&self, req: &ServiceRequest) -> Vec<(String, Quota)> {
+        // Matched on the path as the router sees it; `match_pattern` would use
+        // the raw path and let `/sign%69n` escape the rules for `/signin`
+        let path = req.match_info().as_str();
+        let pattern = req.request().resource_map().match_pattern(path).unwrap_or_else(|| path.to_string());
+        let method = req.method();
+
+        let matches = |rule: &&RateLimitRule| {
+            rule.pattern == pattern && rule.method.as_ref().is_none_or(|m| m == method)
+        };
+        let mut rules: Vec<&RateLimitRule> = self.rules.iter().filter(matches).collect();
+        if rules.is_empty() {
+            rules.extend(self.rules.iter().filter(|rule| rule.pattern == "*"));
+        }
+
+        let ip = self.client_ip.client_ip(req.request()).map(|ip| ip.to_string()).unwrap_or_default();
+        rules
+            .into_iter()
+            .filter_map(|rule| {
+                let quota = rule.quota?;
+                let client = match rule.key_by {
+                    KeyBy::Ip => format!("ip:{}", ip),
+                    KeyBy::Subject => match req.extensions().get::<AuthenticatedUser>() {
+                        Some(user) => format!("sub:{}", user.uid),
+                        None => format!("ip:{}", ip),
+                    },
+                    KeyBy::Route => "all".to_string(),
+                };
+                Some((format!("{} {} {}", method, rule.pattern, client), quota))
+            })
+            .collect()
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(decision.limit));
+    headers.insert(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(decision.remaining));
+    headers.insert(HeaderName::from_static("ratelimit-reset"), HeaderValue::from(decision.reset_secs));
+}
@@ -0,0 +1,138 @@
+pub mod memory;
+pub mod middleware;
+pub mod postgres;
+
+use actix_web::http::Method;
+use futures::future::BoxFuture;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use self::memory::MemoryBackend;
+use self::postgres::PostgresBackend;
+use crate::config::settings::{RateLimitBackendKind, RateLimitSettings};
+
+// Errors from a rate limit backend
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+// What a request is counted against
+#[derive(Debug, Clone, Copy)]
+pub enum KeyBy {
+    // The client IP address
+    Ip,
+    // The authenticated user, falling back to the IP for anonymous requests
+    Subject,
+    // Every client together, e.g. to cap the outbound email volume
+    Route,
+}
+
+// A token bucket: up to `burst` requests at once, refilled at `per_second`
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Quota {
+    pub fn per_minute(requests: u32) -> Self {
+        Self { burst: requests, per_second: requests as f64 / 60.0 }
+    }
+
+    pub fn per_hour(requests: u32) -> Self {
+        Self { burst: requests, per_second: requests as f64 / 3600.0 }
+    }
+}
+
+pub struct RateLimitRule {
+    // `None` matches any method
+    pub method: Option<Method>,
+    // Route pattern as registered in `main.rs`, `*` for the fallback rule
+    pub pattern: &'static str,
+    pub key_by: KeyBy,
+    // `None` exempts the route, not even the `*` rule applies
+    pub quota: Option<Quota>,
+}
+
+impl RateLimitRule {
+    fn new(method: Method, pattern: &'static str, key_by: KeyBy, quota: Quota) -> Self {
+        Self { method: Some(method), pattern, key_by, quota: Some(quota) }
+    }
+
+    fn exempt(method: Method, pattern: &'static str) -> Self {
+        Self { method: Some(method), pattern, key_by: KeyBy::Route, quota: None }
+    }
+}
+
+// Every quota in one place. All rules matching a request apply; the `*` rule
+// only applies to requests no other rule matched.
+pub fn default_rules() -> Vec<RateLimitRule> {
+    vec![
+        // Each of these sends an email, keep them strict
+        RateLimitRule::new(Method::POST, "/signup", KeyBy::Ip, Quota::per_hour(5)),
+        RateLimitRule::new(Method::POST, "/signup", KeyBy::Route, Quota::per_minute(100)),
+        RateLimitRule::new(Method::POST, "/password/forgot", KeyBy::Ip, Quota::per_hour(5)),
+        RateLimitRule::new(Method::POST, "/password/forgot", KeyBy::Route, Quota::per_minute(100)),
+        // Password guessing is handled by the login throttle, this bounds
+        // the Argon2 work a single client can cause
+        RateLimitRule::new(Method::POST, "/signin", KeyBy::Ip, Quota::per_minute(20)),
+        RateLimitRule::new(Method::POST, "/setpassword", KeyBy::Ip, Quota::per_minute(10)),
+        RateLimitRule::new(Method::POST, "/password/reset", KeyBy::Ip, Quota::per_minute(10)),
+        RateLimitRule::new(Method::POST, "/me/password", KeyBy::Subject, Quota::per_minute(5)),
+        RateLimitRule::new(Method::POST, "/token/refresh", KeyBy::Ip, Quota::per_minute(60)),
+        RateLimitRule::new(Method::GET, "/users/{id}", KeyBy::Subject, Quota::per_minute(600)),
+        // Probes must answer even when the backend's database does not
+        RateLimitRule::exempt(Method::GET, "/healthz"),
+        RateLimitRule::exempt(Method::GET, "/readyz"),
+        RateLimitRule { method: None, pattern: "*", key_by: KeyBy::Subject, quota: Some(Quota::per_minute(300)) },
+    ]
+}
+
+// Outcome of counting a request against one quota
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until the bucket is full again
+    pub reset_secs: u64,
+    // Seconds until the next request would be allowed
+    pub retry_after_secs: u64,
+}
+
+// Storage for the token buckets
+pub trait RateLimitBackend: Send + Sync {
+    // Takes one token from the bucket `key`, if there is one
+    fn hit<'a>(&'a self, key: &'a str, quota: Quota) -> BoxFuture<'a, Result<Decision, RateLimitError>>;
+
+    // Forgets buckets that have been full for a while
+    fn purge(&self) -> BoxFuture<'_, Result<u64, RateLimitError>>;
+}
+
+// Builds the backend picked by the `[rate_limit]` settings
+pub fn backend_from_settings(pool: PgPool, settings: &RateLimitSettings) -> Arc<dyn RateLimitBackend> {
+    match settings.backend {
+        RateLimitBackendKind::Memory => Arc::new(MemoryBackend::default()),
+        RateLimitBackendKind::Postgres => Arc::new(PostgresBackend::new(pool)),
+    }
+}
+
+// Refills a bucket holding `tokens` for `elapsed` seconds and tries to take a
+// token. Returns the tokens left and the decision.
+fn take_token(tokens: f64, elapsed: f64, quota: Quota) -> (f64, Decision) {
+    let burst = quota.burst as f64;
+    let available = (tokens + elapsed.max(0.0) * quota.per_second).min(burst);
+    let allowed = available >= 1.0;
+    let left = if allowed { available - 1.0 } else { available };
+
+    let decision = Decision {
+        allowed,
+        limit: quota.burst,
+        remaining: left.floor() as u32,
+        reset_secs: ((burst - left) / quota.per_second).ceil() as u64,
+        retry_after_secs: if allowed { 0 } else { ((1.0 - left) / quota.per_second).ceil() as u64 },
+    };
+    (left, decision)
+}
@@ -0,0 +1,68 @@
+use futures::future::BoxFuture;
+use sqlx::PgPool;
+
+use super::{take_token, Decision, Quota, RateLimitBackend, RateLimitError};
+
+// Buckets stored in Postgres and shared by every instance. Timestamps come
+// from the database clock so instances with skewed clocks agree.
+pub struct PostgresBackend {
+    pool: PgPool,
+}
+
+impl PostgresBackend {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn take(&self, key: &str, quota: Quota) -> Result<Decision, RateLimitError> {
+        let mut tx = self.pool.begin().await?;
+
+        // Lock the bucket so concurrent requests take tokens one at a time
+        let stored = sqlx::query!(
+            r#"
+            SELECT tokens, EXTRACT(EPOCH FROM NOW() - updated_at)::DOUBLE PRECISION AS "elapsed!"
+            FROM rate_limits
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+            key
+        )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let (tokens, elapsed) = stored.map_or((quota.burst as f64, 0.0), |row| (row.tokens, row.elapsed));
+        let (left, decision) = take_token(tokens, elapsed, quota);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO rate_limits (key, tokens, updated_at, full_at)
+            VALUES ($1, $2, NOW(), NOW() + make_interval(secs => $3))
+            ON CONFLICT (key) DO UPDATE
+            SET tokens = EXCLUDED.tokens, updated_at = EXCLUDED.updated_at, full_at = EXCLUDED.full_at
+            "#,
+            key,
+            left,
+            decision.reset_secs as f64
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(decision)
+    }
+}
+
+impl RateLimitBackend for PostgresBackend {
+    fn hit<'a>(&'a self, key: &'a str, quota: Quota) -> BoxFuture<'a, Result<Decision, RateLimitError>> {
+        Box::pin(self.take(key, quota))
+    }
+
+    fn purge(&self) -> BoxFuture<'_, Result<u64, RateLimitError>> {
+        Box::pin(async move {
+            let result = sqlx::query!("DELETE FROM rate_limits WHERE full_at < NOW()")
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+    }
+}
@@ -0,0 +1,43 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct EmailVerificationRepository {
+    pool: PgPool,
+}
+
+impl EmailVerificationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // Records a verification email that is about to be sent. Links from any
+    // earlier email to the same address stop working.
+    pub async fn create(&self, id: Uuid, email: &str, expires_at: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE email_verifications
+            SET invalidated_at = NOW()
+            WHERE LOWER(email) = LOWER($1) AND consumed_at IS NULL AND invalidated_at IS NULL
+            "#,
+            email
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO email_verifications (id, email, expires_at)
+            VALUES ($1, $2, to_timestamp($3))
+            "#,
+            id,
+            email,
+            expires_at as f64
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+}
@@ -1 +1,4 @@
-pub mod user_repository;
\ No newline at end of file
+pub mod user_repository;
+pub mod refresh_token_repository;
+pub mod email_verification_repository;
+pub mod password_reset_repository;pub mod role_repository;
@@ -0,0 +1,43 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct PasswordResetRepository {
+    pool: PgPool,
+}
+
+impl PasswordResetRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // Records a password reset email that is about to be sent. Links from any
+    // earlier reset email for the same user stop working.
+    pub async fn create(&self, id: Uuid, user_uid: Uuid, expires_at: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE password_resets
+            SET invalidated_at = NOW()
+            WHERE user_uid = $1 AND consumed_at IS NULL AND invalidated_at IS NULL
+            "#,
+            user_uid
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO password_resets (id, user_uid, expires_at)
+            VALUES ($1, $2, to_timestamp($3))
+            "#,
+            id,
+            user_uid,
+            expires_at as f64
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+}
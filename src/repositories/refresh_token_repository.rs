@@ -0,0 +1,157 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Refresh tokens are valid for 30 days unless rotated or revoked first
+const REFRESH_TOKEN_LIFETIME_SECS: f64 = 30.0 * 24.0 * 3600.0;
+
+// Outcome of presenting a refresh token
+pub enum RefreshRotation {
+    // The token was valid and has been exchanged for a new one
+    Rotated { user_uid: Uuid, refresh_token: String },
+    // The token had already been used, so its whole family was revoked
+    Reused,
+    // Unknown, expired or revoked token
+    Invalid,
+}
+
+pub struct RefreshTokenRepository {
+    pool: PgPool,
+}
+
+impl RefreshTokenRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // Issues a refresh token starting a new token family for the user
+    pub async fn issue(&self, user_uid: Uuid) -> Result<String, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let token = insert_token(&mut tx, user_uid, Uuid::new_v4()).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    // Exchanges a refresh token for a new one in the same family. Presenting a
+    // token that was already exchanged means it leaked, so every token of the
+    // family is revoked.
+    pub async fn rotate(&self, token: &str) -> Result<RefreshRotation, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // Lock the row so concurrent refreshes with the same token serialize
+        let Some(stored) = sqlx::query!(
+            r#"
+            SELECT id, family_id, user_uid, used_at,
+                   revoked_at IS NOT NULL AS "revoked!",
+                   expires_at <= NOW() AS "expired!"
+            FROM refresh_tokens
+            WHERE token_hash = $1
+            FOR UPDATE
+            "#,
+            hash_token(token)
+        )
+            .fetch_optional(&mut *tx)
+            .await? else {
+            return Ok(RefreshRotation::Invalid);
+        };
+
+        if stored.revoked || stored.expired {
+            return Ok(RefreshRotation::Invalid);
+        }
+
+        if stored.used_at.is_some() {
+            sqlx::query!(
+                r#"
+                UPDATE refresh_tokens
+                SET revoked_at = NOW()
+                WHERE family_id = $1 AND revoked_at IS NULL
+                "#,
+                stored.family_id
+            )
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(RefreshRotation::Reused);
+        }
+
+        sqlx::query!(
+            "UPDATE refresh_tokens SET used_at = NOW() WHERE id = $1",
+            stored.id
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        let refresh_token = insert_token(&mut tx, stored.user_uid, stored.family_id).await?;
+        tx.commit().await?;
+
+        Ok(RefreshRotation::Rotated { user_uid: stored.user_uid, refresh_token })
+    }
+
+    // Revokes the family the given token belongs to, e.g. on logout
+    pub async fn revoke_family(&self, token: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE revoked_at IS NULL
+              AND family_id = (SELECT family_id FROM refresh_tokens WHERE token_hash = $1)
+            "#,
+            hash_token(token)
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Revokes every refresh token of the user
+    pub async fn revoke_all_for_user(&self, user_uid: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_uid = $1 AND revoked_at IS NULL",
+            user_uid
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+async fn insert_token(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_uid: Uuid,
+    family_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    let token = generate_opaque_token();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens (id, family_id, user_uid, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+        "#,
+        Uuid::new_v4(),
+        family_id,
+        user_uid,
+        hash_token(&token),
+        REFRESH_TOKEN_LIFETIME_SECS
+    )
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(token)
+}
+
+// 256 bits of randomness, URL safe so clients can pass it around easily
+fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Only a hash is stored so a database leak does not expose usable tokens
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
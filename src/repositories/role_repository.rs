@@ -0,0 +1,51 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::models::role::Grants;
+
+pub struct RoleRepository {
+    pool: PgPool,
+}
+
+impl RoleRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // Roles held by the user and every permission they grant
+    pub async fn grants_for_user(&self, user_uid: Uuid) -> Result<Grants, sqlx::Error> {
+        let roles = sqlx::query_scalar!(
+            "SELECT role FROM user_roles WHERE user_uid = $1 ORDER BY role",
+            user_uid
+        )
+            .fetch_all(&self.pool)
+            .await?;
+
+        let permissions = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT rp.permission
+            FROM user_roles ur
+            JOIN role_permissions rp ON rp.role = ur.role
+            WHERE ur.user_uid = $1
+            ORDER BY rp.permission
+            "#,
+            user_uid
+        )
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(Grants { roles, permissions })
+    }
+
+    // Gives the user a role; granting one they already hold does nothing
+    pub async fn grant_role(&self, user_uid: Uuid, role: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO user_roles (user_uid, role) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            user_uid,
+            role
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,38 +1,33 @@
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use argon2::password_hash::rand_core::OsRng;
-use argon2::password_hash::SaltString;
-use crate::models::user::{CreateUserRequest, User};
+use argon2::PasswordHash;
+use crate::auth::hash_pool::HashError;
+use crate::auth::password_hash::Argon2Hasher;
+use crate::models::user::{CreateUserRequest, User, UserStatus};
 use sqlx::PgPool;
+use tracing::{error, info};
 use uuid::Uuid;
 
 pub struct UserRepository {
     pool: PgPool,
+    hasher: Argon2Hasher,
 }
 
 impl UserRepository {
-    pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+    pub fn new(pool: PgPool, hasher: Argon2Hasher) -> Self {
+        Self { pool, hasher }
     }
 
-    // Creates a new user with a hashed password
-    pub async fn create_user(&self, user: CreateUserRequest) -> Result<User, sqlx::Error> {
+    // Creates a new, already verified user with a hashed password
+    pub async fn create_user(&self, user: CreateUserRequest) -> Result<User, AuthError> {
         let uid = Uuid::new_v4();
-
-        // Hash the password using Argon2 - a secure password hashing algorithm
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(user.password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
+        let password_hash = self.hasher.hash(&user.password).await?;
 
         // Insert the user with the hashed password
         let user = sqlx::query_as!(
             User,
             r#"
-            INSERT INTO users (uid, email, password)
-            VALUES ($1, $2, $3)
-            RETURNING uid, email, password
+            INSERT INTO users (uid, email, password, status)
+            VALUES ($1, $2, $3, 'verified')
+            RETURNING uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
             "#,
             uid,
             user.email,
@@ -44,11 +39,152 @@ impl UserRepository {
         Ok(user)
     }
 
+    // Records a signup for an email nobody has signed up with yet. Returns
+    // `None` if the email got taken in the meantime.
+    pub async fn create_pending_user(&self, email: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"
+            INSERT INTO users (uid, email, status)
+            VALUES ($1, $2, 'pending_verification')
+            ON CONFLICT (LOWER(email)) DO NOTHING
+            RETURNING uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
+            "#,
+            Uuid::new_v4(),
+            email
+        )
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    // Consumes an email verification and moves the pending user to verified
+    // with the given password, in a single transaction. Returns `None` when
+    // the verification is unknown, expired, superseded by a newer email or
+    // already used, or the user is already verified.
+    pub async fn verify_user(
+        &self,
+        verification_id: Uuid,
+        password: &str,
+    ) -> Result<Option<User>, AuthError> {
+        // Hash before opening the transaction so no connection is held while
+        // waiting for a hash worker
+        let password_hash = self.hasher.hash(password).await?;
+        let mut tx = self.pool.begin().await?;
+
+        let Some(verification) = sqlx::query!(
+            r#"
+            UPDATE email_verifications
+            SET consumed_at = NOW()
+            WHERE id = $1
+              AND consumed_at IS NULL
+              AND invalidated_at IS NULL
+              AND expires_at > NOW()
+            RETURNING email
+            "#,
+            verification_id
+        )
+            .fetch_optional(&mut *tx)
+            .await? else {
+            return Ok(None);
+        };
+
+        // Verifications sent before users had a status have no pending row,
+        // those users are created on the spot
+        let Some(user) = sqlx::query_as!(
+            User,
+            r#"
+            INSERT INTO users (uid, email, password, status)
+            VALUES ($1, $2, $3, 'verified')
+            ON CONFLICT (LOWER(email)) DO UPDATE
+            SET password = EXCLUDED.password, status = 'verified'
+            WHERE users.status = 'pending_verification'
+            RETURNING uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
+            "#,
+            Uuid::new_v4(),
+            verification.email,
+            password_hash
+        )
+            .fetch_optional(&mut *tx)
+            .await? else {
+            return Ok(None);
+        };
+
+        tx.commit().await?;
+        Ok(Some(user))
+    }
+
+    // Consumes a password reset and stores the new password hash in a single
+    // transaction. Returns `None` when the reset is unknown, expired,
+    // superseded by a newer email or already used.
+    pub async fn reset_password(&self, reset_id: Uuid, password: &str) -> Result<Option<User>, AuthError> {
+        let password_hash = self.hasher.hash(password).await?;
+        let mut tx = self.pool.begin().await?;
+
+        let Some(reset) = sqlx::query!(
+            r#"
+            UPDATE password_resets
+            SET consumed_at = NOW()
+            WHERE id = $1
+              AND consumed_at IS NULL
+              AND invalidated_at IS NULL
+              AND expires_at > NOW()
+            RETURNING user_uid
+            "#,
+            reset_id
+        )
+            .fetch_optional(&mut *tx)
+            .await? else {
+            return Ok(None);
+        };
+
+        // Any other reset links still sitting in the inbox become useless
+        sqlx::query!(
+            r#"
+            UPDATE password_resets
+            SET invalidated_at = NOW()
+            WHERE user_uid = $1 AND consumed_at IS NULL AND invalidated_at IS NULL
+            "#,
+            reset.user_uid
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users
+            SET password = $2
+            WHERE uid = $1
+            RETURNING uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
+            "#,
+            reset.user_uid,
+            password_hash
+        )
+            .fetch_one(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(Some(user))
+    }
+
+    // Replaces the password hash of an existing user
+    pub async fn update_password(&self, uid: Uuid, password: &str) -> Result<(), AuthError> {
+        let password_hash = self.hasher.hash(password).await?;
+        sqlx::query!(
+            "UPDATE users SET password = $2 WHERE uid = $1",
+            uid,
+            password_hash
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn get_user_by_id(&self, uid: Uuid) -> Result<Option<User>, sqlx::Error> {
         sqlx::query_as!(
             User,
             r#"
-            SELECT uid, email, password
+            SELECT uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
             FROM users
             WHERE uid = $1
             "#,
@@ -62,9 +198,9 @@ impl UserRepository {
         sqlx::query_as!(
             User,
             r#"
-            SELECT uid, email, password
+            SELECT uid, email, password, status AS "status: UserStatus", created_at AS "created_at!"
             FROM users
-            WHERE email = $1
+            WHERE LOWER(email) = LOWER($1)
             "#,
             email
         )
@@ -72,39 +208,77 @@ impl UserRepository {
             .await
     }
 
-    // New method: Authenticate a user by verifying their password
-    pub async fn authenticate_user(&self, email: &str, password: &str) -> Result<bool, AuthError> {
-        // First, retrieve the user by email
-        let user = self.get_user_by_email(email).await
-            .map_err(|e| AuthError::DatabaseError(e.to_string()))?
-            .ok_or(AuthError::UserNotFound)?;
+    // Authenticate a user by verifying their password, returning the user on
+    // success. Unknown emails go through a dummy verification so they take as
+    // long as a wrong password and cannot be told apart.
+    pub async fn authenticate_user(&self, email: &str, password: &str) -> Result<Option<User>, AuthError> {
+        // First, retrieve the user by email. Pending users have no password
+        // yet and are treated like unknown ones.
+        let found = self.get_user_by_email(email).await?
+            .and_then(|user| user.password.clone().map(|hash| (user, hash)));
+        let Some((user, password_hash)) = found else {
+            self.hasher.verify_dummy(password).await?;
+            return Ok(None);
+        };
 
         // Verify the password using Argon2
-        let parsed_hash = PasswordHash::new(&user.password)
+        let parsed_hash = PasswordHash::new(&password_hash)
             .map_err(|e| AuthError::HashError(e.to_string()))?;
 
-        Ok(Argon2::default()
-            .verify_password(password.as_bytes(), &parsed_hash)
-            .is_ok())
+        if !self.hasher.verify(password, &password_hash).await? {
+            return Ok(None);
+        }
+
+        // Upgrade hashes made with older or weaker parameters while we have
+        // the plaintext at hand. Failing to do so must not fail the login.
+        if self.hasher.needs_rehash(&parsed_hash) {
+            let rehashed = match self.hasher.hash(password).await {
+                Ok(rehashed) => rehashed,
+                Err(e) => {
+                    error!("Failed to rehash password for user {}: {}", user.uid, e);
+                    return Ok(Some(user));
+                }
+            };
+            match self.replace_password_hash(user.uid, &password_hash, &rehashed).await {
+                Ok(()) => info!("Rehashed password for user: {}", user.uid),
+                Err(e) => error!("Failed to store rehashed password for user {}: {}", user.uid, e),
+            }
+        }
+
+        Ok(Some(user))
+    }
+
+    // Swaps the hash only if it is still the one we verified against, so a
+    // concurrent password change is not overwritten
+    async fn replace_password_hash(&self, uid: Uuid, old_hash: &str, new_hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE users SET password = $3 WHERE uid = $1 AND password = $2",
+            uid,
+            old_hash,
+            new_hash
+        )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 }
 
 // Custom error type for authentication-related errors
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum AuthError {
-    DatabaseError(String),
-    UserNotFound,
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Password hash error: {0}")]
     HashError(String),
+    #[error("Too many password hashes in progress")]
+    Busy,
 }
 
-impl std::fmt::Display for AuthError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            AuthError::DatabaseError(e) => write!(f, "Database error: {}", e),
-            AuthError::UserNotFound => write!(f, "User not found"),
-            AuthError::HashError(e) => write!(f, "Password hash error: {}", e),
+impl From<HashError> for AuthError {
+    fn from(e: HashError) -> Self {
+        match e {
+            HashError::Busy => AuthError::Busy,
+            HashError::Failed(message) => AuthError::HashError(message),
         }
     }
 }
-
-impl std::error::Error for AuthError {}
\ No newline at end of file
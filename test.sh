@@ -1,23 +1,209 @@
 #!/bin/bash
 
-# First create a user and capture the response
-echo "Creating new user..."
-response=$(curl -s -X POST \
-  http://localhost:8080/users \
+# Integration tests against a running server on localhost:8080. Needs psql
+# access to the same database through $DATABASE_URL, and $ADMIN_EMAIL and
+# $ADMIN_PASSWORD set to the admin account the server bootstrapped.
+BASE_URL="http://localhost:8080"
+failures=0
+
+check() {
+  local description=$1 expected=$2 actual=$3
+  if [ "$expected" == "$actual" ]; then
+    echo "PASS: $description"
+  else
+    echo "FAIL: $description (expected '$expected', got '$actual')"
+    failures=$((failures + 1))
+  fi
+}
+
+sql() {
+  psql "$DATABASE_URL" -tA -c "$1"
+}
+
+# Runs a query until it returns the expected value, for up to 10 seconds, and
+# prints the last result. For work the server does in the background.
+wait_for_sql() {
+  local expected=$1 query=$2 result
+  for _ in $(seq 1 100); do
+    result=$(sql "$query")
+    [ "$result" == "$expected" ] && break
+    sleep 0.1
+  done
+  echo "$result"
+}
+
+signup() {
+  curl -s -X POST "$BASE_URL/signup" \
+    -H "Content-Type: application/json" \
+    -d "{\"email\": \"$1\"}"
+}
+
+# Fails the check if the response leaks a password hash in any form
+check_no_hash() {
+  local description=$1 body=$2
+  check "$description has no password field" "false" "$(echo "$body" | jq 'has("password")')"
+  check "$description has no password hash" "" "$(echo "$body" | grep -o '\$argon2')"
+}
+
+signin() {
+  curl -s -X POST "$BASE_URL/signin" \
+    -H "Content-Type: application/json" \
+    -d "{\"email\": \"$1\", \"password\": \"$2\"}"
+}
+
+# Health endpoints
+echo "Probing liveness and readiness..."
+check "healthz answers without a session" "200" \
+  "$(curl -s -o /dev/null -w '%{http_code}' "$BASE_URL/healthz")"
+response=$(curl -s -w '\n%{http_code}' "$BASE_URL/readyz")
+check "readyz answers without a session" "200" "$(echo "$response" | tail -n 1)"
+check "readyz reports ready" "ready" "$(echo "$response" | head -n 1 | jq -r '.status')"
+check "readyz checks every dependency" "database,email,migrations" \
+  "$(echo "$response" | head -n 1 | jq -r '.checks | keys | join(",")')"
+
+# User endpoints
+echo "Signing in as the admin..."
+admin_token=$(signin "$ADMIN_EMAIL" "$ADMIN_PASSWORD" | jq -r '.token')
+
+echo "Sending malformed requests..."
+response=$(curl -s -w '\n%{http_code}' -X POST "$BASE_URL/signin" \
+  -H "Content-Type: application/json" \
+  -d '{"email": 1}')
+check "malformed body is a bad request" "400" "$(echo "$response" | tail -n 1)"
+check "malformed body gets the error envelope" "bad_request" "$(echo "$response" | head -n 1 | jq -r '.code')"
+response=$(curl -s -w '\n%{http_code}' "$BASE_URL/users/not-a-uuid" -H "Authorization: Bearer $admin_token")
+check "malformed path is a bad request" "400" "$(echo "$response" | tail -n 1)"
+check "malformed path gets the error envelope" "bad_request" "$(echo "$response" | head -n 1 | jq -r '.code')"
+
+echo "Creating a user..."
+user_email="user-$(date +%s)-$RANDOM@example.com"
+user_password="correct-horse-battery-staple"
+response=$(curl -s -w '\n%{http_code}' -X POST "$BASE_URL/users" \
+  -H "Authorization: Bearer $admin_token" \
   -H "Content-Type: application/json" \
-  -d '{
-    "email": "test@example.com",
-    "password": "secretpassword123"
-  }')
-
-# Extract the ID from the response using jq (if you have it installed)
-# You'll need to install jq: 'sudo apt-get install jq' on Ubuntu/Debian
-user_id=$(echo $response | jq -r '.id')
-
-echo "Created user with ID: $user_id"
-
-# Get the user we just created
-echo "Fetching user details..."
-curl -X GET \
-  "http://localhost:8080/users/$user_id" \
-  -H "Accept: application/json"
\ No newline at end of file
+  -d "{\"email\": \"$user_email\", \"password\": \"$user_password\"}")
+check "admin creates the user" "201" "$(echo "$response" | tail -n 1)"
+response=$(echo "$response" | head -n 1)
+user_id=$(echo "$response" | jq -r '.uid')
+check "created user is returned" "$user_email" "$(echo "$response" | jq -r '.email')"
+check_no_hash "created user" "$response"
+
+echo "Creating the same user again..."
+response=$(curl -s -X POST "$BASE_URL/users" \
+  -H "Authorization: Bearer $admin_token" \
+  -H "Content-Type: application/json" \
+  -d "{\"email\": \"$(echo "$user_email" | tr a-z A-Z)\", \"password\": \"$user_password\"}")
+check "duplicate email is a conflict" "conflict" "$(echo "$response" | jq -r '.code')"
+
+echo "Fetching the user as the admin..."
+response=$(curl -s "$BASE_URL/users/$user_id" -H "Authorization: Bearer $admin_token")
+check "admin reads the user" "$user_id" "$(echo "$response" | jq -r '.uid')"
+check_no_hash "user read by the admin" "$response"
+
+echo "Fetching the user as themselves..."
+response=$(signin "$user_email" "$user_password")
+check_no_hash "signin response" "$response"
+user_token=$(echo "$response" | jq -r '.token')
+response=$(curl -s "$BASE_URL/users/$user_id" -H "Authorization: Bearer $user_token")
+check "user reads themselves" "verified" "$(echo "$response" | jq -r '.status')"
+check_no_hash "user read by themselves" "$response"
+
+echo "Fetching another user as a non-admin..."
+admin_id=$(sql "SELECT uid FROM users WHERE LOWER(email) = LOWER('$ADMIN_EMAIL')")
+check "another user's id looks missing" "404" \
+  "$(curl -s -o /dev/null -w '%{http_code}' "$BASE_URL/users/$admin_id" -H "Authorization: Bearer $user_token")"
+check "a missing id gets the same 404" "404" \
+  "$(curl -s -o /dev/null -w '%{http_code}' "$BASE_URL/users/00000000-0000-0000-0000-000000000000" \
+    -H "Authorization: Bearer $user_token")"
+
+echo "Creating a user as a non-admin..."
+check "non-admin may not create users" "403" \
+  "$(curl -s -o /dev/null -w '%{http_code}' -X POST "$BASE_URL/users" \
+    -H "Authorization: Bearer $user_token" \
+    -H "Content-Type: application/json" \
+    -d "{\"email\": \"other-$user_email\", \"password\": \"$user_password\"}")"
+
+echo "Creating a user through a percent-encoded path as a non-admin..."
+check "encoded admin-only path is still forbidden" "403" \
+  "$(curl -s -o /dev/null -w '%{http_code}' -X POST "$BASE_URL/user%73" \
+    -H "Authorization: Bearer $user_token" \
+    -H "Content-Type: application/json" \
+    -d "{\"email\": \"encoded-$user_email\", \"password\": \"$user_password\"}")"
+
+echo "Rotating the signing key as a non-admin..."
+check "non-admin may not rotate signing keys" "403" \
+  "$(curl -s -o /dev/null -w '%{http_code}' -X POST "$BASE_URL/admin/keys/rotate" \
+    -H "Authorization: Bearer $user_token")"
+
+echo "Guessing the current password with a session..."
+for attempt in 1 2 3 4 5; do
+  response=$(curl -s -X POST "$BASE_URL/me/password" \
+    -H "Authorization: Bearer $user_token" \
+    -H "Content-Type: application/json" \
+    -d "{\"current_password\": \"wrong-guess-$attempt\", \"new_password\": \"another-horse-battery-staple\"}")
+done
+check "wrong current password is rejected" "invalid_credentials" "$(echo "$response" | jq -r '.code')"
+check "guessing the current password locks the account" "account_locked" \
+  "$(signin "$user_email" "$user_password" | jq -r '.code')"
+
+sql "DELETE FROM users WHERE email = '$user_email'" > /dev/null
+# The guesses also count against this machine's IP
+sql "DELETE FROM login_failures WHERE key IN ('account:$user_email', 'ip:127.0.0.1', 'ip:::1')" > /dev/null
+
+# Signup flow
+#
+# The verification links only go out by email, so the database is inspected
+# instead. /signup is rate limited to 5 requests per hour per IP, so restart
+# the server (memory backend) before running this again.
+signup_message='{"message":"Check your inbox to continue signing up"}'
+new_email="signup-$(date +%s)-$RANDOM@example.com"
+
+echo "Signing up a new email..."
+check "new email gets the generic response" "$signup_message" "$(signup "$new_email")"
+check "new email creates a pending user" "pending_verification" \
+  "$(wait_for_sql "pending_verification" "SELECT status FROM users WHERE email = '$new_email'")"
+check "new email records a verification" "1" \
+  "$(wait_for_sql "1" "SELECT COUNT(*) FROM email_verifications WHERE email = '$new_email'")"
+
+echo "Signing up again before verifying..."
+check "pending email gets the generic response" "$signup_message" "$(signup "$new_email")"
+check "pending email invalidates the earlier verification" "1" \
+  "$(wait_for_sql "1" "SELECT COUNT(*) FROM email_verifications WHERE email = '$new_email' AND invalidated_at IS NOT NULL")"
+check "pending email records a new verification" "1" \
+  "$(wait_for_sql "1" "SELECT COUNT(*) FROM email_verifications WHERE email = '$new_email' AND invalidated_at IS NULL")"
+check "pending email stays pending" "pending_verification" \
+  "$(sql "SELECT status FROM users WHERE email = '$new_email'")"
+
+echo "Signing in before verifying..."
+check "pending user cannot sign in" "invalid_credentials" \
+  "$(curl -s -X POST "$BASE_URL/signin" \
+    -H "Content-Type: application/json" \
+    -d "{\"email\": \"$new_email\", \"password\": \"correct-horse-battery-staple\"}" | jq -r '.code')"
+
+echo "Setting a password with a bad verification token..."
+check "bad token is rejected" "401" \
+  "$(curl -s -o /dev/null -w '%{http_code}' -X POST "$BASE_URL/setpassword" \
+    -H "Authorization: Bearer not-a-token" \
+    -H "Content-Type: application/json" \
+    -d '{"password": "correct-horse-battery-staple"}')"
+
+echo "Signing up an already verified email..."
+verified_email="verified-$(date +%s)-$RANDOM@example.com"
+sql "INSERT INTO users (uid, email, password, status) VALUES (gen_random_uuid(), '$verified_email', 'x', 'verified')" > /dev/null
+check "verified email gets the generic response" "$signup_message" "$(signup "$verified_email")"
+# Nothing is written for a verified email, so wait for a signup sent after it
+# to be processed instead
+later_email="later-$(date +%s)-$RANDOM@example.com"
+signup "$later_email" > /dev/null
+check "a later signup is processed" "1" \
+  "$(wait_for_sql "1" "SELECT COUNT(*) FROM email_verifications WHERE email = '$later_email'")"
+check "verified email stays verified" "verified" \
+  "$(sql "SELECT status FROM users WHERE email = '$verified_email'")"
+check "verified email records no verification" "0" \
+  "$(sql "SELECT COUNT(*) FROM email_verifications WHERE email = '$verified_email'")"
+
+sql "DELETE FROM users WHERE email IN ('$new_email', '$verified_email', '$later_email')" > /dev/null
+sql "DELETE FROM email_verifications WHERE email IN ('$new_email', '$later_email')" > /dev/null
+
+echo "$failures failure(s)"
+exit $((failures > 0))